@@ -3,12 +3,12 @@ use std::path::Path;
 
 use hound;
 
-fn read_wav(path: &Path) -> (Vec<f32>, Vec<f32>, u32) {
-    let mut reader = hound::WavReader::open(path).unwrap();
+fn read_wav(path: &Path) -> Result<(Vec<f32>, Vec<f32>, u32), hound::Error> {
+    let mut reader = hound::WavReader::open(path)?;
     let spec = reader.spec();
     let sample_rate = spec.sample_rate;
 
-    let samples: Vec<i32> = reader.samples::<i32>().map(|s| s.unwrap()).collect();
+    let samples: Vec<i32> = reader.samples::<i32>().collect::<Result<_, _>>()?;
 
     let mut left = Vec::new();
     let mut right = Vec::new();
@@ -20,7 +20,7 @@ fn read_wav(path: &Path) -> (Vec<f32>, Vec<f32>, u32) {
         }
     }
 
-    (left, right, sample_rate)
+    Ok((left, right, sample_rate))
 }
 
 fn dominant_freq(samples: &[f32], sample_rate: u32) -> f32 {
@@ -46,9 +46,48 @@ fn dominant_freq(samples: &[f32], sample_rate: u32) -> f32 {
     max_idx as f32 * sample_rate as f32 / samples.len() as f32
 }
 
+/// The strongest frequency between `lo` and `hi` Hz, to a fraction of an FFT bin. A Hann window
+/// keeps nearby tones from leaking into the band, and the peak is refined by fitting a parabola
+/// to the log magnitudes around it. None if the band is empty or silent.
+pub fn peak_freq(samples: &[f32], sample_rate: u32, lo: f32, hi: f32) -> Option<f32> {
+    let n = samples.len();
+    if n < 4 {
+        return None;
+    }
+    let mut planner = FftPlanner::<f32>::new();
+    let fft = planner.plan_fft_forward(n);
+    let mut buffer: Vec<Complex<f32>> = samples
+        .iter()
+        .enumerate()
+        .map(|(i, &s)| {
+            let w = 0.5 - 0.5 * (std::f32::consts::TAU * i as f32 / n as f32).cos();
+            Complex { re: s * w, im: 0.0 }
+        })
+        .collect();
+    fft.process(&mut buffer);
+
+    let bin_hz = sample_rate as f32 / n as f32;
+    let first = ((lo / bin_hz).floor() as usize).max(1);
+    let last = ((hi / bin_hz).ceil() as usize).min(n / 2 - 1);
+    let peak =
+        (first..=last).max_by(|a, b| buffer[*a].norm_sqr().total_cmp(&buffer[*b].norm_sqr()))?;
+    if buffer[peak].norm_sqr() == 0.0 {
+        return None;
+    }
+    let db = |i: usize| (buffer[i].norm_sqr() + f32::MIN_POSITIVE).ln();
+    let (a, b, c) = (db(peak - 1), db(peak), db(peak + 1));
+    let denom = a - 2.0 * b + c;
+    let offset = if denom != 0.0 {
+        (0.5 * (a - c) / denom).clamp(-0.5, 0.5)
+    } else {
+        0.0
+    };
+    Some((peak as f32 + offset) * bin_hz)
+}
+
 /// Provided a path, analyze it and find the dominant frequencies in each channel.
-pub fn analyze(path: &Path) {
-    let (left, right, sr) = read_wav(path);
+pub fn analyze(path: &Path) -> Result<(), hound::Error> {
+    let (left, right, sr) = read_wav(path)?;
 
     let freq_left = dominant_freq(&left, sr);
     let freq_right = dominant_freq(&right, sr);
@@ -58,6 +97,7 @@ pub fn analyze(path: &Path) {
         "Binaural beat frequency: {:.2} Hz",
         (freq_left - freq_right).abs()
     );
+    Ok(())
 }
 
 #[cfg(test)]
@@ -105,6 +145,19 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_peak_freq_between_bins() {
+        // 4s has 0.25 Hz bins, and both tones fall between them.
+        let sr = 8000;
+        let left = make_sine_wave(200.1, sr, 4.0);
+        let right = make_sine_wave(204.6, sr, 4.0);
+        let l = peak_freq(&left, sr, 190.0, 215.0).unwrap();
+        let r = peak_freq(&right, sr, 190.0, 215.0).unwrap();
+        assert!((l - 200.1).abs() < 0.02, "left was {l}");
+        assert!((r - l - 4.5).abs() < 0.02, "beat was {}", r - l);
+        assert_eq!(peak_freq(&[0.0; 800], sr, 190.0, 215.0), None);
+    }
+
     #[test]
     fn test_dominant_freq_two_tones_picks_strongest() {
         let sr = 8000;
@@ -22,6 +22,6 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         panic!("path does not exist");
     }
     info!("Processing file: {}", path.display());
-    analysis::analyze(&path);
+    analysis::analyze(&path)?;
     Ok(())
 }
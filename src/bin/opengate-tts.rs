@@ -2,6 +2,7 @@ use clap::Parser;
 use log::info;
 use std::fs;
 
+use opengate::config::VoiceSpec;
 use opengate::logger;
 use opengate::tts::run_piper;
 
@@ -30,6 +31,24 @@ struct Args {
     )]
     config: Option<String>,
 
+    #[arg(long = "length-scale", help = "speaking speed, where larger is slower")]
+    length_scale: Option<f32>,
+
+    #[arg(long = "noise-scale", help = "how much the voice varies")]
+    noise_scale: Option<f32>,
+
+    #[arg(long = "noise-w", help = "how much the phoneme lengths vary")]
+    noise_w: Option<f32>,
+
+    #[arg(long = "speaker", help = "speaker id for multi-speaker models")]
+    speaker: Option<u32>,
+
+    #[arg(
+        long = "sentence-silence",
+        help = "seconds of silence after each sentence"
+    )]
+    sentence_silence: Option<f32>,
+
     #[arg(short, long, help = "text file to turn into speech")]
     input: String,
 
@@ -45,6 +64,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     logger::init(args.verbose);
 
     let text = fs::read_to_string(&args.input)?;
+    let voice = VoiceSpec {
+        length_scale: args.length_scale,
+        noise_scale: args.noise_scale,
+        noise_w: args.noise_w,
+        speaker: args.speaker,
+        sentence_silence: args.sentence_silence,
+    };
 
     run_piper(
         args.piper_bin.as_deref(),
@@ -52,6 +78,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         &args.model,
         args.config.as_deref(),
         &args.out,
+        &voice,
     )?;
 
     info!("TTS wrote: {}", args.out);
@@ -1,21 +1,30 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use log::info;
 use serde_yaml::Value;
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use yaml_merge_keys::merge_keys_serde;
 
+use opengate::cache::{self, PruneOptions, Usage, format_size};
 use opengate::config::Config;
-use opengate::logger;
-use opengate::render::render;
+use opengate::render::{RenderOptions, render};
+use opengate::sink::{AudioFormat, BitDepth, BitrateMode, Dither, SINKS};
+use opengate::{logger, sysconfig, tts};
 
 #[derive(Parser, Debug)]
 #[command(
     author,
     version,
-    about = "generate binaural beats for meditative purposes"
+    about = "generate binaural beats for meditative purposes",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
 )]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(
         short,
         long,
@@ -31,37 +40,341 @@ struct Args {
     )]
     force: bool,
 
+    #[arg(
+        long = "skip-broken-mixins",
+        help = "warn about and leave out audio mixins that fail to decode, rather than failing the render"
+    )]
+    skip_broken_mixins: bool,
+
+    #[arg(
+        short = 'j',
+        long = "jobs",
+        default_value_t = 0,
+        help = "how many piper processes to run at once when generating TTS (0 for one per CPU)"
+    )]
+    jobs: usize,
+
+    #[arg(
+        long = "subtitles",
+        help = "also write the TTS as subtitles, in srt, vtt or lrc format depending on the extension"
+    )]
+    subtitles: Option<String>,
+
+    #[arg(
+        long = "bit-depth",
+        help = "sample format of the output: 16, 24 or 32f (float), overriding the config's bit_depth"
+    )]
+    bit_depth: Option<BitDepth>,
+
+    #[arg(
+        long = "dither",
+        help = "dither when reducing to 16 or 24-bit: none, tpdf or shaped, overriding the config's dither"
+    )]
+    dither: Option<Dither>,
+
+    #[arg(
+        long = "dither-seed",
+        help = "seed for the dither, so it's the same on every run"
+    )]
+    dither_seed: Option<u64>,
+
+    #[arg(
+        long = "bitrate",
+        value_name = "KBPS",
+        help = "target bitrate of opus, ogg or mp3 output, overriding the config's bitrate"
+    )]
+    bitrate: Option<u32>,
+
+    #[arg(
+        long = "bitrate-mode",
+        help = "cbr or vbr for opus or mp3 output, overriding the config's bitrate_mode"
+    )]
+    bitrate_mode: Option<BitrateMode>,
+
+    #[arg(
+        long = "verify",
+        help = "decode the output once it's written and check each segment's beat survived encoding"
+    )]
+    verify: bool,
+
+    #[arg(
+        long = "format",
+        help = "output format, rather than going by the extension of --out: see --list-formats"
+    )]
+    format: Option<AudioFormat>,
+
+    #[arg(
+        long = "raw",
+        conflicts_with = "format",
+        help = "write bare PCM samples with no header: s16le, s24le or f32le depending on --bit-depth, same as --format raw"
+    )]
+    raw: bool,
+
+    #[arg(
+        long = "list-formats",
+        help = "list the output formats and whether this build supports them"
+    )]
+    list_formats: bool,
+
     /// YAML configuration file
-    config: PathBuf,
+    #[arg(required_unless_present = "list_formats")]
+    config: Option<PathBuf>,
 
     #[arg(
         short,
         long,
         default_value = "opengate.wav",
-        help = "output file, supporting wav or flac"
+        help = "output file, its format going by the extension, or - to stream WAV to stdout"
     )]
     out: String,
 
-    #[arg(short = 'v', long = "verbose", help = "verbose level logging")]
+    #[arg(
+        short = 'v',
+        long = "verbose",
+        global = true,
+        help = "verbose level logging"
+    )]
     verbose: bool,
 }
 
-fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
-    let cfg_text = fs::read_to_string(&args.config)?;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage the generated TTS clips and decoded audio in the audio dirs
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheAction {
+    /// Show how much disk each audio dir uses
+    List {
+        #[arg(
+            long = "audio-dir",
+            help = "also list this audio dir, eg: a config's custom audio_dir"
+        )]
+        audio_dirs: Vec<PathBuf>,
+
+        #[arg(long, help = "list every clip, with its age and text")]
+        clips: bool,
+    },
+    /// Remove clips the given configs no longer use, or older than some number of days
+    Prune {
+        /// Configs whose clips to keep. Only their audio dirs are pruned.
+        configs: Vec<PathBuf>,
+
+        #[arg(
+            long = "older-than",
+            value_name = "DAYS",
+            help = "also remove clips created more than this many days ago"
+        )]
+        older_than: Option<f64>,
+
+        #[arg(long = "dry-run", help = "only show what would be removed")]
+        dry_run: bool,
+    },
+    /// Remove every generated file from the audio dirs
+    Clear {
+        #[arg(
+            long = "audio-dir",
+            help = "only clear this audio dir, rather than every one in the cache"
+        )]
+        audio_dir: Option<PathBuf>,
+
+        #[arg(long = "dry-run", help = "only show what would be removed")]
+        dry_run: bool,
+    },
+}
+
+fn load_config(path: &Path) -> Result<Config, Box<dyn std::error::Error>> {
+    let cfg_text = fs::read_to_string(path)?;
     let value: Value = serde_yaml::from_str(&cfg_text)?;
     let merged = merge_keys_serde(value)?;
     let mut cfg: Config = serde_yaml::from_value(merged)?;
     // This *MUST* run before render because audio and tts specs func init_paths uses the calculated paths.
-    cfg.normalize_paths(&args.config);
-    render(cfg, &args.out, args.piper_bin.as_deref(), args.force)?;
-    info!("Wrote beats to: {:?}", &args.out);
+    cfg.normalize_paths(path);
+    Ok(cfg)
+}
+
+/// eg: "3 days", "5 hours"
+fn format_age(age: Duration) -> String {
+    let hours = age.as_secs() / 3600;
+    match hours {
+        0 => format!("{} minutes", age.as_secs() / 60),
+        1..48 => format!("{} hours", hours),
+        _ => format!("{} days", hours / 24),
+    }
+}
+
+fn print_usage(verb: &str, dir: &Path, usage: Usage) {
+    println!(
+        "{} {} files, {} in {}",
+        verb,
+        usage.files,
+        format_size(usage.bytes),
+        dir.display()
+    );
+}
+
+fn run_cache(action: CacheAction) -> Result<(), Box<dyn std::error::Error>> {
+    let root = sysconfig::get_audio_root()?;
+    match action {
+        CacheAction::List { audio_dirs, clips } => {
+            let mut dirs = cache::audio_dirs(&root)?;
+            dirs.extend(audio_dirs);
+            let mut total = Usage::default();
+            for dir in dirs.iter() {
+                let files = cache::list_dir(dir)?;
+                let usage = Usage::of(&files);
+                println!(
+                    "{}: {} files, {}",
+                    dir.display(),
+                    usage.files,
+                    format_size(usage.bytes)
+                );
+                total.files += usage.files;
+                total.bytes += usage.bytes;
+                if clips {
+                    for file in files.iter() {
+                        let text = file
+                            .entry
+                            .as_ref()
+                            .map(|e| format!("{:?}", tts::snippet(&e.text)))
+                            .unwrap_or_default();
+                        println!(
+                            "  {}  {}  {} old  {}",
+                            file.name(),
+                            format_size(file.size),
+                            format_age(file.age()),
+                            text
+                        );
+                    }
+                }
+            }
+            println!("total: {} files, {}", total.files, format_size(total.bytes));
+        }
+        CacheAction::Prune {
+            configs,
+            older_than,
+            dry_run,
+        } => {
+            if configs.is_empty() && older_than.is_none() {
+                return Err(
+                    "give the configs whose clips to keep, --older-than DAYS, or both".into(),
+                );
+            }
+            let older_than = older_than.map(|days| Duration::from_secs_f64(days * 86400.0));
+            let verb = if dry_run { "would remove" } else { "removed" };
+            if configs.is_empty() {
+                for dir in cache::audio_dirs(&root)? {
+                    let opts = PruneOptions {
+                        referenced: None,
+                        older_than,
+                        dry_run,
+                    };
+                    print_usage(verb, &dir, cache::prune(&dir, &opts)?);
+                }
+                return Ok(());
+            }
+            // Configs can share an audio dir, so keep what any of them uses.
+            let mut referenced: BTreeMap<PathBuf, HashSet<PathBuf>> = BTreeMap::new();
+            for path in configs.iter() {
+                let (dir, files) = load_config(path)?.referenced_cache_files()?;
+                referenced.entry(dir).or_default().extend(files);
+            }
+            for (dir, files) in referenced.iter() {
+                let opts = PruneOptions {
+                    referenced: Some(files),
+                    older_than,
+                    dry_run,
+                };
+                print_usage(verb, dir, cache::prune(dir, &opts)?);
+            }
+        }
+        CacheAction::Clear { audio_dir, dry_run } => {
+            let dirs = match audio_dir {
+                Some(dir) => vec![dir],
+                None => cache::audio_dirs(&root)?,
+            };
+            let verb = if dry_run { "would remove" } else { "removed" };
+            for dir in dirs.iter() {
+                print_usage(verb, dir, cache::clear(dir, dry_run)?);
+            }
+        }
+    }
     Ok(())
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// eg: "opus    .opus      Ogg Opus, lossy (needs --features opus)"
+fn list_formats() {
+    for info in SINKS.iter() {
+        let extensions: Vec<String> = info.extensions.iter().map(|e| format!(".{}", e)).collect();
+        let missing = match (info.is_built(), info.feature) {
+            (false, Some(feature)) => format!(" (needs --features {})", feature),
+            _ => String::new(),
+        };
+        println!(
+            "{:<8}{:<11}{}{}",
+            info.name,
+            extensions.join(" "),
+            info.description,
+            missing
+        );
+    }
+}
+
+fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(Command::Cache { action }) = args.command {
+        return run_cache(action);
+    }
+    if args.list_formats {
+        list_formats();
+        return Ok(());
+    }
+    let Some(config) = args.config else {
+        return Err("a config is required".into());
+    };
+    let cfg = load_config(&config)?;
+    let opts = RenderOptions {
+        piper_bin: args.piper_bin,
+        force: args.force,
+        skip_broken_mixins: args.skip_broken_mixins,
+        jobs: args.jobs,
+        subtitles: args.subtitles,
+        bit_depth: args.bit_depth,
+        dither: args.dither,
+        dither_seed: args.dither_seed,
+        bitrate: args.bitrate,
+        bitrate_mode: args.bitrate_mode,
+        verify: args.verify,
+        format: if args.raw {
+            Some(AudioFormat::Raw)
+        } else {
+            args.format
+        },
+    };
+    render(cfg, &args.out, &opts)?;
+    if args.out != opengate::sink::STDOUT {
+        info!("Wrote beats to: {:?}", &args.out);
+    }
+    Ok(())
+}
+
+fn main() {
     let args = Args::parse();
-    logger::init(args.verbose);
-    run(args)
+    // Keep the logs out of the audio when it's going to stdout.
+    if args.out == opengate::sink::STDOUT {
+        logger::init_stderr(args.verbose);
+    } else {
+        logger::init(args.verbose);
+    }
+    // Print errors with Display rather than Debug, since ours name where in the config they are.
+    // They go straight to stderr, since the log may be on stdout or filtered out by RUST_LOG.
+    if let Err(err) = run(args) {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
 }
 
 #[cfg(test)]
@@ -78,9 +391,22 @@ mod tests {
         let out_path = dir.path().join("out.wav");
 
         let args = Args {
+            command: None,
             piper_bin: None,
             force: false,
-            config: config_path.clone(),
+            skip_broken_mixins: false,
+            jobs: 0,
+            subtitles: None,
+            bit_depth: None,
+            dither: None,
+            dither_seed: None,
+            bitrate: None,
+            bitrate_mode: None,
+            verify: false,
+            format: None,
+            raw: false,
+            list_formats: false,
+            config: Some(config_path.clone()),
             out: out_path.to_string_lossy().to_string(),
             verbose: false,
         };
@@ -0,0 +1,334 @@
+/// The generated files in audio dirs: TTS clips, their speech fx, and decoded copies of mixins,
+/// with a manifest saying what each clip is, so old ones can be listed and cleaned up.
+use crate::config::TTSSpec;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const TTS_PREFIX: &str = "_tts_";
+pub const PCM_PREFIX: &str = "_pcm_";
+pub const FX_PREFIX: &str = "_fx_";
+pub const MANIFEST_FILE: &str = "_manifest.yaml";
+
+/// What a cached TTS clip is, since its name is just a hash.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ManifestEntry {
+    pub key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub engine: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    pub text: String,
+    /// Seconds since the unix epoch
+    pub created: u64,
+}
+
+/// The clips in one audio dir, by file name.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub clips: BTreeMap<String, ManifestEntry>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Manifest {
+    /// An audio dir without a manifest, or with one that can't be read, has an empty one.
+    pub fn load(dir: &Path) -> Manifest {
+        let path = dir.join(MANIFEST_FILE);
+        match fs::read_to_string(&path) {
+            Ok(text) => serde_yaml::from_str(&text).unwrap_or_else(|err| {
+                log::warn!("ignoring unreadable cache manifest {:?}: {}", path, err);
+                Manifest::default()
+            }),
+            Err(_) => Manifest::default(),
+        }
+    }
+
+    pub fn save(&self, dir: &Path) -> std::io::Result<()> {
+        let text = serde_yaml::to_string(self).map_err(std::io::Error::other)?;
+        // Written under another name first, so an interrupted run never leaves half a manifest.
+        let partial = dir.join(format!("{}.partial", MANIFEST_FILE));
+        fs::write(&partial, text)?;
+        fs::rename(&partial, dir.join(MANIFEST_FILE))
+    }
+
+    /// Add a generated clip, keeping when it was first created if it's already here.
+    pub fn record(&mut self, spec: &TTSSpec) {
+        let Some(name) = spec._out_path.file_name().map(|n| n.to_string_lossy()) else {
+            return;
+        };
+        if self.clips.contains_key(name.as_ref()) || !spec.is_cached() {
+            return;
+        }
+        let engine = spec.engine.engine.map(|e| e.name().to_string());
+        self.clips.insert(
+            name.to_string(),
+            ManifestEntry {
+                key: name.trim_start_matches(TTS_PREFIX).to_string(),
+                engine,
+                model: spec.model.clone(),
+                text: spec.text.trim().to_string(),
+                created: now_secs(),
+            },
+        );
+    }
+}
+
+/// A generated file in an audio dir.
+#[derive(Debug)]
+pub struct CacheFile {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Seconds since the unix epoch, from the manifest or else the file's modified time
+    pub created: u64,
+    pub entry: Option<ManifestEntry>,
+}
+
+impl CacheFile {
+    pub fn name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(now_secs().saturating_sub(self.created))
+    }
+}
+
+/// Whether a file name is something opengate generated, rather than a user's own audio.
+pub fn is_cache_file(name: &str) -> bool {
+    [TTS_PREFIX, PCM_PREFIX, FX_PREFIX]
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+}
+
+/// Every audio dir under the cache root, eg: ~/.cache/opengate/audio/*
+pub fn audio_dirs(root: &Path) -> std::io::Result<Vec<PathBuf>> {
+    if !root.exists() {
+        return Ok(Vec::new());
+    }
+    let mut dirs: Vec<PathBuf> = fs::read_dir(root)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_dir())
+        .collect();
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// The generated files in an audio dir, by name.
+pub fn list_dir(dir: &Path) -> std::io::Result<Vec<CacheFile>> {
+    let manifest = Manifest::load(dir);
+    let mut files: Vec<CacheFile> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !is_cache_file(&name) {
+            continue;
+        }
+        let meta = entry.metadata()?;
+        let entry = manifest.clips.get(&name).cloned();
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        files.push(CacheFile {
+            path: dir.join(&name),
+            size: meta.len(),
+            created: entry.as_ref().map(|e| e.created).unwrap_or(modified),
+            entry,
+        });
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+/// How many files and bytes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+impl Usage {
+    pub fn of(files: &[CacheFile]) -> Usage {
+        Usage {
+            files: files.len(),
+            bytes: files.iter().map(|f| f.size).sum(),
+        }
+    }
+}
+
+/// What to remove from an audio dir. A file goes if it isn't in `referenced` (when given) or is
+/// older than `older_than` (when given).
+#[derive(Debug, Default)]
+pub struct PruneOptions<'a> {
+    pub referenced: Option<&'a HashSet<PathBuf>>,
+    pub older_than: Option<Duration>,
+    /// Only report what would be removed
+    pub dry_run: bool,
+}
+
+/// Remove the generated files the options select, and their manifest entries. Returns what was
+/// removed.
+pub fn prune(dir: &Path, opts: &PruneOptions) -> std::io::Result<Usage> {
+    let files = list_dir(dir)?;
+    let doomed: Vec<CacheFile> = files
+        .into_iter()
+        .filter(|file| {
+            let unreferenced = opts
+                .referenced
+                .is_some_and(|referenced| !referenced.contains(&file.path));
+            let old = opts.older_than.is_some_and(|max| file.age() > max);
+            unreferenced || old
+        })
+        .collect();
+    let usage = Usage::of(&doomed);
+    if opts.dry_run {
+        for file in doomed.iter() {
+            info!("would remove {:?}", file.path);
+        }
+        return Ok(usage);
+    }
+
+    let mut manifest = Manifest::load(dir);
+    for file in doomed.iter() {
+        debug!("removing {:?}", file.path);
+        fs::remove_file(&file.path)?;
+        manifest.clips.remove(&file.name());
+    }
+    if !doomed.is_empty() && dir.join(MANIFEST_FILE).exists() {
+        manifest.save(dir)?;
+    }
+    Ok(usage)
+}
+
+/// Remove every generated file in an audio dir, and its manifest.
+pub fn clear(dir: &Path, dry_run: bool) -> std::io::Result<Usage> {
+    let usage = prune(
+        dir,
+        &PruneOptions {
+            referenced: Some(&HashSet::new()),
+            older_than: None,
+            dry_run,
+        },
+    )?;
+    let manifest = dir.join(MANIFEST_FILE);
+    if !dry_run && manifest.exists() {
+        fs::remove_file(manifest)?;
+    }
+    Ok(usage)
+}
+
+/// Bytes for people, eg: "12.3 MB"
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(dir: &Path, name: &str, text: &str) -> TTSSpec {
+        let mut spec: TTSSpec =
+            serde_yaml::from_str(&format!("model: m.onnx\ntext: {}", text)).unwrap();
+        spec._out_path = dir.join(name);
+        spec
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(999), "999 B");
+        assert_eq!(format_size(12_345_678), "12.3 MB");
+    }
+
+    #[test]
+    fn test_manifest_record_and_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let clip = spec(dir.path(), "_tts_abc", "Hello there.");
+        let mut manifest = Manifest::default();
+        // Not generated yet, so there's nothing to record.
+        manifest.record(&clip);
+        assert!(manifest.clips.is_empty());
+
+        fs::write(&clip._out_path, b"RIFF").unwrap();
+        manifest.record(&clip);
+        manifest.save(dir.path()).unwrap();
+        let entry = &Manifest::load(dir.path()).clips["_tts_abc"];
+        assert_eq!(entry.key, "abc");
+        assert_eq!(entry.model.as_deref(), Some("m.onnx"));
+        assert_eq!(entry.text, "Hello there.");
+    }
+
+    #[test]
+    fn test_prune_and_clear() {
+        let dir = tempfile::tempdir().unwrap();
+        let keep = spec(dir.path(), "_tts_keep", "keep");
+        let old = spec(dir.path(), "_tts_old", "old");
+        let mut manifest = Manifest::default();
+        for clip in [&keep, &old] {
+            fs::write(&clip._out_path, b"RIFF").unwrap();
+            manifest.record(clip);
+        }
+        manifest.clips.get_mut("_tts_old").unwrap().created = 0;
+        manifest.save(dir.path()).unwrap();
+        fs::write(dir.path().join("_pcm_def_48000"), b"RIFFRIFF").unwrap();
+        // A user's own audio is never touched.
+        fs::write(dir.path().join("rain.wav"), b"RIFF").unwrap();
+        assert_eq!(Usage::of(&list_dir(dir.path()).unwrap()).bytes, 16);
+
+        let removed = prune(
+            dir.path(),
+            &PruneOptions {
+                older_than: Some(Duration::from_secs(86400)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(removed, Usage { files: 1, bytes: 4 });
+        assert!(!old._out_path.exists());
+        assert!(!Manifest::load(dir.path()).clips.contains_key("_tts_old"));
+
+        let referenced: HashSet<PathBuf> = [keep._out_path.clone()].into_iter().collect();
+        let opts = PruneOptions {
+            referenced: Some(&referenced),
+            dry_run: true,
+            ..Default::default()
+        };
+        assert_eq!(prune(dir.path(), &opts).unwrap().files, 1);
+        assert!(dir.path().join("_pcm_def_48000").exists());
+        let opts = PruneOptions {
+            dry_run: false,
+            ..opts
+        };
+        assert_eq!(prune(dir.path(), &opts).unwrap().files, 1);
+        assert!(keep._out_path.exists());
+
+        assert_eq!(clear(dir.path(), false).unwrap().files, 1);
+        assert!(!keep._out_path.exists());
+        assert!(!dir.path().join(MANIFEST_FILE).exists());
+        assert!(dir.path().join("rain.wav").exists());
+    }
+}
@@ -1,14 +1,24 @@
 use serde::Deserialize;
 use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
 use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
-use crate::fileutils::to_absolute;
-use crate::mixin::Mixin;
+use crate::cache::{FX_PREFIX, Manifest};
+use crate::fileutils::{resolve, resolve_relative, to_absolute};
+use crate::lexicon::Lexicon;
+use crate::markup::{self, MarkupError, Piece, Span};
+use crate::mixin::{self, Mixin};
 use crate::noise::NoiseColor;
+use crate::sink::{BitDepth, BitrateMode, Chapter, Dither, Metadata};
+use crate::speechfx;
 use crate::sysconfig;
 use crate::timeutils::DurationSeconds;
-use crate::tts::run_piper;
+use crate::tts::{self, BatchLine, EngineError, TtsError, TtsRequest};
 use crate::utils::{ms_to_samples, secs_to_samples};
 use log::{debug, info};
 
@@ -16,6 +26,18 @@ use log::{debug, info};
 const DEFAULT_SAMPLE_RATE: u32 = 48_000;
 const DEFAULT_GAIN: f32 = 0.95;
 const DEFAULT_FADE_MS: f32 = 50.0;
+const DEFAULT_DUCK_AMOUNT_DB: f32 = 12.0;
+const DEFAULT_DUCK_ATTACK_MS: f32 = 50.0;
+const DEFAULT_DUCK_RELEASE_MS: f32 = 500.0;
+const DEFAULT_COMPRESS_THRESHOLD_DB: f32 = -20.0;
+const DEFAULT_COMPRESS_RATIO: f32 = 3.0;
+const DEFAULT_COMPRESS_ATTACK_MS: f32 = 5.0;
+const DEFAULT_COMPRESS_RELEASE_MS: f32 = 80.0;
+const DEFAULT_REVERB_MIX: f32 = 0.12;
+const DEFAULT_REVERB_ROOM: f32 = 0.3;
+const DEFAULT_REVERB_DAMPING: f32 = 0.5;
+const DEFAULT_TRIM_THRESHOLD_DB: f32 = -50.0;
+const DEFAULT_TRIM_PAD_MS: f32 = 20.0;
 
 #[derive(Debug, Deserialize, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
@@ -24,6 +46,19 @@ pub enum Curve {
     Exp,
 }
 
+/// What happens when a mixin is longer than what's left of its segment.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// Cut it off at the end of the segment, with a warning.
+    Truncate,
+    /// Keep playing it over the following segments.
+    #[default]
+    Continue,
+    /// Fail the render.
+    Error,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     /// Optional overrides
@@ -33,6 +68,47 @@ pub struct Config {
     pub gain: Option<f32>,
     #[serde(default)]
     pub fade_ms: Option<f32>,
+    /// Sample format of the output, eg: 24 or 32f for a master
+    #[serde(default)]
+    pub bit_depth: Option<BitDepth>,
+    /// Dither when reducing to 16 or 24-bit: none (default), tpdf or shaped
+    #[serde(default)]
+    pub dither: Option<Dither>,
+    /// Makes the dither the same on every run
+    #[serde(default)]
+    pub dither_seed: Option<u64>,
+    /// Target bitrate in kbps for opus, ogg and mp3 output
+    #[serde(default)]
+    pub bitrate: Option<u32>,
+    /// cbr or vbr, for opus and mp3 output
+    #[serde(default)]
+    pub bitrate_mode: Option<BitrateMode>,
+    /// Tags to write into the output, eg: its title
+    #[serde(default)]
+    pub meta: Option<Metadata>,
+    /// Lower the tone and noise while any mixin is playing, unless a segment overrides it
+    #[serde(default)]
+    pub duck: Option<DuckSpec>,
+    /// What to do with mixins that run past the end of their segment, unless a mixin overrides it
+    #[serde(default)]
+    pub overflow: Option<Overflow>,
+    /// Default piper voice settings for every TTS line
+    #[serde(default)]
+    pub voice: VoiceSpec,
+    /// Pronunciation fixes for every TTS line, as word: replacement
+    #[serde(default)]
+    pub lexicon: BTreeMap<String, String>,
+    /// A YAML file of more pronunciation fixes, relative to the config. `lexicon` wins over it.
+    #[serde(default)]
+    pub lexicon_file: Option<PathBuf>,
+    #[serde(skip)]
+    pub _lexicon_file: Option<PathBuf>,
+    /// Post-processing for every TTS line, unless a line overrides it
+    #[serde(default)]
+    pub speech_fx: Option<SpeechFxSpec>,
+    /// Trimming the silence around every TTS line, unless a line overrides it
+    #[serde(default)]
+    pub trim: Option<TrimSetting>,
 
     /// A path to the working directory where it caches the results of generated audio, or looks
     /// for audio file mixins
@@ -86,6 +162,118 @@ fn default_audio_gain() -> f32 {
     1.0f32
 }
 
+/// Piper voice settings, for one TTS line or as defaults for all of them. Anything left out uses
+/// the model's own defaults.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct VoiceSpec {
+    /// Speaking speed, where 1.0 is normal and larger is slower
+    #[serde(default)]
+    pub length_scale: Option<f32>,
+    /// How much the voice varies
+    #[serde(default)]
+    pub noise_scale: Option<f32>,
+    /// How much the phoneme lengths vary
+    #[serde(default)]
+    pub noise_w: Option<f32>,
+    /// Speaker id, for multi-speaker models like libritts
+    #[serde(default)]
+    pub speaker: Option<u32>,
+    /// Seconds of silence after each sentence
+    #[serde(default)]
+    pub sentence_silence: Option<f32>,
+}
+
+impl VoiceSpec {
+    /// Fill in anything not set here from `defaults`.
+    pub fn or(&self, defaults: &VoiceSpec) -> VoiceSpec {
+        VoiceSpec {
+            length_scale: self.length_scale.or(defaults.length_scale),
+            noise_scale: self.noise_scale.or(defaults.noise_scale),
+            noise_w: self.noise_w.or(defaults.noise_w),
+            speaker: self.speaker.or(defaults.speaker),
+            sentence_silence: self.sentence_silence.or(defaults.sentence_silence),
+        }
+    }
+
+    /// Only the settings that were given, eg: "length_scale=1.2::speaker=3", so adding this to
+    /// the cache key leaves keys of lines without any settings unchanged.
+    fn cache_key(&self) -> String {
+        let mut parts: Vec<String> = Vec::new();
+        if let Some(v) = self.length_scale {
+            parts.push(format!("length_scale={}", v));
+        }
+        if let Some(v) = self.noise_scale {
+            parts.push(format!("noise_scale={}", v));
+        }
+        if let Some(v) = self.noise_w {
+            parts.push(format!("noise_w={}", v));
+        }
+        if let Some(v) = self.speaker {
+            parts.push(format!("speaker={}", v));
+        }
+        if let Some(v) = self.sentence_silence {
+            parts.push(format!("sentence_silence={}", v));
+        }
+        parts.join("::")
+    }
+}
+
+/// The program that speaks TTS lines.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Engine {
+    #[default]
+    Piper,
+    EspeakNg,
+    /// Any other engine, run from the `cmd` template
+    Command,
+}
+
+/// Which engine speaks a TTS line, and its settings for engines other than piper.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct EngineSpec {
+    #[serde(default)]
+    pub engine: Option<Engine>,
+    /// Command template for `engine: command`, eg: "mytts --out {out} --voice {voice}"
+    #[serde(default)]
+    pub cmd: Option<String>,
+    /// The engine's own name for the voice, eg: "en-us" for espeak-ng
+    #[serde(default)]
+    pub voice_name: Option<String>,
+}
+
+impl Engine {
+    /// As it's written in configs, eg: "espeak-ng"
+    pub fn name(&self) -> &'static str {
+        match self {
+            Engine::Piper => "piper",
+            Engine::EspeakNg => "espeak-ng",
+            Engine::Command => "command",
+        }
+    }
+}
+
+impl EngineSpec {
+    pub fn get_engine(&self) -> Engine {
+        self.engine.unwrap_or_default()
+    }
+
+    /// Like `VoiceSpec::cache_key`, only what was given, so piper lines keep their keys.
+    fn cache_key(&self) -> String {
+        let mut parts: Vec<String> = Vec::new();
+        if let Some(engine) = self.engine.filter(|e| *e != Engine::Piper) {
+            parts.push(format!("engine={:?}", engine));
+        }
+        if let Some(cmd) = &self.cmd {
+            parts.push(format!("cmd={}", cmd));
+        }
+        if let Some(voice_name) = &self.voice_name {
+            parts.push(format!("voice_name={}", voice_name));
+        }
+        parts.join("::")
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct TTSSpec {
     #[serde(default = "default_offset")]
@@ -96,36 +284,230 @@ pub struct TTSSpec {
     /// Key is used for caching. Otherwise, it'd calculate the sha256 hash of the
     /// model::config::text
     pub key: Option<String>,
-    pub model: String,
+    /// The piper model, which other engines may use too
+    #[serde(default)]
+    pub model: Option<String>,
     pub config: Option<String>,
+    #[serde(flatten)]
+    pub engine: EngineSpec,
+    #[serde(flatten)]
+    pub voice: VoiceSpec,
+    #[serde(default)]
+    pub overflow: Option<Overflow>,
+    #[serde(default)]
+    pub speech_fx: Option<SpeechFxSpec>,
+    #[serde(default)]
+    pub trim: Option<TrimSetting>,
     #[serde(skip)]
     _model_path: PathBuf,
     #[serde(skip)]
     _config_path: PathBuf,
     #[serde(skip)]
     pub _out_path: PathBuf,
+    /// Pronunciation fixes from the config, applied to the text before it's spoken
+    #[serde(skip)]
+    pub _lexicon: Option<Arc<Lexicon>>,
+    /// The clip after trimming and `speech_fx`, if there's any processing to do
+    #[serde(skip)]
+    pub _fx_path: Option<PathBuf>,
+}
+
+/// The config's settings for every TTS line, for what a line doesn't set itself.
+#[derive(Debug, Clone, Default)]
+pub struct LineDefaults {
+    pub voice: VoiceSpec,
+    pub overflow: Option<Overflow>,
+    pub speech_fx: Option<SpeechFxSpec>,
+    pub trim: Option<TrimSetting>,
+    pub lexicon: Option<Arc<Lexicon>>,
 }
 
 impl TTSSpec {
-    pub fn init_paths(&mut self, audio_dir: &Path, model_dir: &Path) -> std::io::Result<()> {
-        self._model_path = model_dir.join(self.model.clone());
-        self._config_path = model_dir.join(format!("{}.json", self.model.clone()));
-        if let Some(config_str) = &self.config {
-            self._config_path = model_dir.join(config_str);
+    /// Fill in anything the line doesn't set from the config's defaults.
+    pub fn apply_defaults(&mut self, defaults: &LineDefaults) {
+        self.voice = self.voice.or(&defaults.voice);
+        self.overflow = self.overflow.or(defaults.overflow);
+        if self.speech_fx.is_none() {
+            self.speech_fx = defaults.speech_fx.clone();
         }
+        self.trim = self.trim.or(defaults.trim);
+        self._lexicon = defaults.lexicon.clone();
+    }
+
+    /// The text that's sent to the engine, with the lexicon applied.
+    pub fn spoken_text(&self) -> Cow<'_, str> {
+        match &self._lexicon {
+            Some(lexicon) => lexicon.apply(&self.text),
+            None => Cow::Borrowed(&self.text),
+        }
+    }
 
-        self._model_path = std::fs::canonicalize(&self._model_path)?;
-        self._config_path = std::fs::canonicalize(&self._config_path)?;
+    pub fn init_paths(&mut self, audio_dir: &Path, model_dir: &Path) -> std::io::Result<()> {
+        let engine = self.engine.get_engine();
+        match &self.model {
+            Some(model) => {
+                self._model_path = resolve(&model_dir.join(model));
+                let config_path = match &self.config {
+                    Some(config_str) => model_dir.join(config_str),
+                    None => model_dir.join(format!("{}.json", model)),
+                };
+                // Only piper needs the config next to the model.
+                self._config_path = if engine == Engine::Piper || self.config.is_some() {
+                    resolve(&config_path)
+                } else {
+                    config_path
+                };
+            }
+            None if engine == Engine::Piper => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "TTS line needs a piper model: {:?}",
+                        tts::snippet(&self.text)
+                    ),
+                ));
+            }
+            None => {}
+        }
+        if engine == Engine::Command && self.engine.cmd.is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "TTS line with engine: command needs a cmd template: {:?}",
+                    tts::snippet(&self.text)
+                ),
+            ));
+        }
         let key = self.get_key();
-        debug!("canonicalized model path and config path for key {}", key);
+        debug!("resolved model path and config path for key {}", key);
 
         self._out_path = audio_dir.join(format!("_tts_{}", key));
+        self._fx_path = self
+            .fx_key()
+            .map(|fx_key| audio_dir.join(format!("{}{}", FX_PREFIX, fx_key)));
         Ok(())
     }
 
-    pub fn generate(&self, piper_bin: Option<&str>, force: bool) -> std::io::Result<()> {
+    /// Check the model, and its config if the engine needs it, are there to generate with.
+    pub fn check_files(&self) -> std::io::Result<()> {
+        if self.model.is_none() {
+            return Ok(());
+        }
+        check_exists("TTS model", &self._model_path)?;
+        // Only piper needs the config next to the model.
+        if self.engine.get_engine() == Engine::Piper || self.config.is_some() {
+            check_exists("TTS model config", &self._config_path)?;
+        }
+        Ok(())
+    }
+
+    /// The clip that's mixed in: the processed one when it's trimmed or has speech fx, or else the
+    /// generated one.
+    pub fn clip_path(&self) -> &Path {
+        self._fx_path.as_deref().unwrap_or(&self._out_path)
+    }
+
+    /// How to trim the silence around the clip, if at all. It's on unless turned off.
+    pub fn trim_spec(&self) -> Option<TrimSpec> {
+        self.trim.unwrap_or_default().spec()
+    }
+
+    /// sha256 of the generated clip's key and the trim and speech fx settings, or `None` when
+    /// there's no processing to do.
+    fn fx_key(&self) -> Option<String> {
+        let trim = self.trim_spec();
+        let fx = self.speech_fx.as_ref().filter(|fx| !fx.is_empty());
+        if trim.is_none() && fx.is_none() {
+            return None;
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(self.get_key().as_bytes());
+        if let Some(trim) = trim {
+            hasher.update(format!("::trim={},{}", trim.threshold_db, trim.pad_ms).as_bytes());
+        }
+        if let Some(fx) = fx {
+            hasher.update("::");
+            hasher.update(fx.cache_key().as_bytes());
+        }
+        Some(hex::encode(hasher.finalize()))
+    }
+
+    /// Trim the generated clip and run the speech fx over it, unless that was already done.
+    pub fn process_clip(&self, force: bool) -> Result<(), hound::Error> {
+        let Some(fx_path) = &self._fx_path else {
+            return Ok(());
+        };
+        let done = std::fs::metadata(fx_path).is_ok_and(|m| m.len() > 0);
+        if done && !force {
+            debug!("using cached processed clip at {:?}", fx_path);
+            return Ok(());
+        }
+        debug!(
+            "processing the clip of {:?}: {:?}",
+            tts::snippet(&self.text),
+            fx_path
+        );
+        let fx = self.speech_fx.as_ref().filter(|fx| !fx.is_empty());
+        speechfx::process_file(&self._out_path, fx_path, self.trim_spec().as_ref(), fx)
+    }
+
+    /// Whether the output file was already generated. An empty file is what's left of a failed
+    /// run, so it doesn't count.
+    pub fn is_cached(&self) -> bool {
+        std::fs::metadata(&self._out_path)
+            .map(|m| m.len() > 0)
+            .unwrap_or(false)
+    }
+
+    /// Split the text by its markup into clips, each with its own spec and cache path, and the
+    /// pauses between them. Plain text gives `None`, and is generated as it is.
+    pub fn script(&self) -> Result<Option<Vec<ScriptPart>>, MarkupError> {
+        let spans = markup::parse(&self.text)?;
+        if !markup::has_markup(&spans) {
+            return Ok(None);
+        }
+        let audio_dir = self._out_path.parent().unwrap_or(Path::new(""));
+        let script = spans
+            .into_iter()
+            .map(|span| match span {
+                Span::Pause(secs) => ScriptPart::Pause(secs),
+                Span::Speech { text, speed } => {
+                    let mut part = self.clone();
+                    part.text = text;
+                    part.key = None;
+                    if speed != 1.0 {
+                        // A longer length scale is slower speech.
+                        part.voice.length_scale =
+                            Some(self.voice.length_scale.unwrap_or(1.0) / speed);
+                    }
+                    part._out_path = audio_dir.join(format!("_tts_{}", part.get_key()));
+                    ScriptPart::Clip(Box::new(part))
+                }
+            })
+            .collect();
+        Ok(Some(script))
+    }
+
+    /// Join the generated clips of a marked up line into this line's output file.
+    pub fn assemble(&self, script: &[ScriptPart]) -> Result<(), hound::Error> {
+        let pieces: Vec<Piece> = script
+            .iter()
+            .map(|part| match part {
+                ScriptPart::Clip(spec) => Piece::Clip(&spec._out_path),
+                ScriptPart::Pause(secs) => Piece::Silence(*secs),
+            })
+            .collect();
+        info!(
+            "assembling {} pieces of marked up TTS into {}",
+            pieces.len(),
+            self._out_path.display()
+        );
+        markup::assemble(&pieces, &self._out_path)
+    }
+
+    pub fn generate(&self, piper_bin: Option<&str>, force: bool) -> Result<(), EngineError> {
         let out_path = self._out_path.to_str().unwrap();
-        if Path::new(out_path).exists() {
+        if self.is_cached() {
             if force {
                 info!(
                     "{} already exists, but forcing regeneration due to --force|-f",
@@ -142,20 +524,67 @@ impl TTSSpec {
             info!("generating TTS: {}", out_path);
         }
 
-        let model_path = self._model_path.to_str().unwrap();
-        let maybe_config_path = self._config_path.to_str();
-        run_piper(
+        let has_model = self.model.is_some();
+        let text = self.spoken_text();
+        let req = TtsRequest {
+            text: &text,
+            model: has_model.then_some(self._model_path.as_path()),
+            config: has_model.then_some(self._config_path.as_path()),
+            voice_name: self.engine.voice_name.as_deref(),
+            voice: &self.voice,
+            out: &self._out_path,
+        };
+        tts::synthesize(tts::backend(&self.engine, piper_bin).as_ref(), &req)
+    }
+
+    /// What lines must share to be generated by one piper process: the model, config and voice
+    /// settings, apart from the speaker. Other engines don't batch.
+    fn batch_key(&self) -> Option<(&Path, &Path, VoiceSpec)> {
+        if self.engine.get_engine() != Engine::Piper || self.model.is_none() {
+            return None;
+        }
+        let voice = VoiceSpec {
+            speaker: None,
+            ..self.voice.clone()
+        };
+        Some((&self._model_path, &self._config_path, voice))
+    }
+
+    /// Generate lines with the same `batch_key` in one piper process. On failure, the index of
+    /// the line that failed is returned with the error.
+    fn generate_batch(
+        specs: &[&TTSSpec],
+        piper_bin: Option<&str>,
+    ) -> Result<(), (usize, EngineError)> {
+        let first = specs[0];
+        let texts: Vec<Cow<str>> = specs.iter().map(|spec| spec.spoken_text()).collect();
+        let lines: Vec<BatchLine> = specs
+            .iter()
+            .zip(texts.iter())
+            .map(|(spec, text)| BatchLine {
+                text,
+                speaker: spec.voice.speaker,
+                out: &spec._out_path,
+            })
+            .collect();
+        let voice = VoiceSpec {
+            speaker: None,
+            ..first.voice.clone()
+        };
+        tts::run_piper_batch(
             piper_bin,
-            &self.text,
-            model_path,
-            maybe_config_path,
-            out_path,
+            &first._model_path,
+            Some(&first._config_path),
+            &voice,
+            &lines,
         )
     }
 
     /// Get or calculate the key being used to cache the output file.
     /// This is calculated with:
-    ///     sha256(abs_model_path . "::" . abs_config_path . "::" . trimmed_text_as_bytes)
+    ///     sha256(model_path . "::" . config_path . "::" . trimmed_text_as_bytes)
+    /// followed by "::" and the voice and engine settings, if any are set. Both paths are
+    /// canonicalized, so a symlinked model dir gives the same key as its target.
     fn get_key(&self) -> String {
         if let Some(k) = &self.key {
             return k.trim().to_string().clone();
@@ -166,7 +595,14 @@ impl TTSSpec {
         hasher.update("::");
         hasher.update(self._config_path.to_string_lossy().as_bytes());
         hasher.update("::");
-        hasher.update(self.text.trim().as_bytes());
+        // The spoken text, so a lexicon entry only changes the keys of lines it applies to.
+        hasher.update(self.spoken_text().trim().as_bytes());
+        for settings_key in [self.voice.cache_key(), self.engine.cache_key()] {
+            if !settings_key.is_empty() {
+                hasher.update("::");
+                hasher.update(settings_key.as_bytes());
+            }
+        }
 
         // Finalize.
         let digest = hasher.finalize();
@@ -174,6 +610,13 @@ impl TTSSpec {
     }
 }
 
+/// A piece of a marked up TTS line.
+#[derive(Debug, Clone)]
+pub enum ScriptPart {
+    Clip(Box<TTSSpec>),
+    Pause(f32),
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct AudioSpec {
     #[serde(default = "default_offset")]
@@ -181,22 +624,361 @@ pub struct AudioSpec {
     #[serde(default = "default_audio_gain")]
     pub gain: f32,
     pub path: String,
+    #[serde(default)]
+    pub overflow: Option<Overflow>,
     #[serde(skip)]
     pub _path: PathBuf,
 }
 
 impl AudioSpec {
     pub fn init_paths(&mut self, audio_dir: &Path) -> std::io::Result<()> {
-        self._path = audio_dir.join(&self.path);
+        self._path = resolve(&audio_dir.join(&self.path));
+        debug!("resolved path for file {} to {:?}", self.path, self._path);
 
-        self._path = std::fs::canonicalize(&self._path)?;
-        debug!(
-            "canonicalized path for file {} to {:?}",
-            self.path, self._path
-        );
+        Ok(())
+    }
+}
 
+/// A not found error naming the file, rather than just the OS's "No such file or directory".
+fn check_exists(what: &str, path: &Path) -> std::io::Result<()> {
+    if path.exists() {
+        return Ok(());
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("{} not found: {:?}", what, path),
+    ))
+}
+
+fn default_narration_gap() -> DurationSeconds {
+    DurationSeconds(1.0f32)
+}
+
+/// One line of a narration, either just its text or with its own gap.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum NarrationLine {
+    Text(String),
+    Line {
+        text: String,
+        /// Silence after this line, instead of the narration's `gap`
+        #[serde(default)]
+        gap: Option<DurationSeconds>,
+    },
+}
+
+impl NarrationLine {
+    pub fn text(&self) -> &str {
+        match self {
+            NarrationLine::Text(text) => text,
+            NarrationLine::Line { text, .. } => text,
+        }
+    }
+}
+
+/// A sequence of TTS lines played one after the other, where each line starts once the previous
+/// one has finished plus a gap, so nothing needs a hand-tuned offset.
+#[derive(Debug, Deserialize, Clone)]
+pub struct NarrationSpec {
+    /// When the first line starts
+    #[serde(default = "default_offset")]
+    pub offset: DurationSeconds,
+    #[serde(default = "default_tts_gain")]
+    pub gain: f32,
+    /// Silence after each line
+    #[serde(default = "default_narration_gap")]
+    pub gap: DurationSeconds,
+    /// Make the segment longer if the narration doesn't fit in its `dur`
+    #[serde(default)]
+    pub extend: bool,
+    #[serde(default)]
+    pub model: Option<String>,
+    pub config: Option<String>,
+    #[serde(flatten)]
+    pub engine: EngineSpec,
+    #[serde(flatten)]
+    pub voice: VoiceSpec,
+    #[serde(default)]
+    pub overflow: Option<Overflow>,
+    #[serde(default)]
+    pub speech_fx: Option<SpeechFxSpec>,
+    #[serde(default)]
+    pub trim: Option<TrimSetting>,
+    pub lines: Vec<NarrationLine>,
+    /// Each line as a TTS spec, with its offset filled in by `sequence`.
+    #[serde(skip)]
+    pub _lines: Vec<TTSSpec>,
+    /// Where the last line ends, in seconds from the start of the segment.
+    #[serde(skip)]
+    pub _end: f32,
+}
+
+impl NarrationSpec {
+    /// Turn each line into a TTS spec with the narration's settings and initialize its paths.
+    pub fn init_lines(
+        &mut self,
+        audio_dir: &Path,
+        model_dir: &Path,
+        defaults: &LineDefaults,
+    ) -> std::io::Result<()> {
+        self._lines = Vec::new();
+        for line in self.lines.iter() {
+            let mut spec = TTSSpec {
+                offset: default_offset(),
+                gain: self.gain,
+                text: line.text().to_string(),
+                key: None,
+                model: self.model.clone(),
+                config: self.config.clone(),
+                engine: self.engine.clone(),
+                voice: self.voice.clone(),
+                overflow: self.overflow,
+                speech_fx: self.speech_fx.clone(),
+                trim: self.trim,
+                _model_path: PathBuf::new(),
+                _config_path: PathBuf::new(),
+                _out_path: PathBuf::new(),
+                _lexicon: None,
+                _fx_path: None,
+            };
+            spec.apply_defaults(defaults);
+            spec.init_paths(audio_dir, model_dir)?;
+            self._lines.push(spec);
+        }
         Ok(())
     }
+
+    /// Once the lines are generated, set each one's offset from the length of the one before. If
+    /// a clip can't be read, the error comes with the index of its line.
+    pub fn sequence(&mut self) -> Result<(), (usize, hound::Error)> {
+        let mut start = self.offset.0;
+        for (i, (line, spec)) in self.lines.iter().zip(self._lines.iter_mut()).enumerate() {
+            let reader = hound::WavReader::open(spec.clip_path()).map_err(|err| (i, err))?;
+            let secs = reader.duration() as f32 / reader.spec().sample_rate as f32;
+            spec.offset = DurationSeconds(start);
+            self._end = start + secs;
+            debug!(
+                "narration line at {:.2}s for {:.2}s: {:?}",
+                start, secs, spec.text
+            );
+            let gap = match line {
+                NarrationLine::Line { gap: Some(gap), .. } => *gap,
+                _ => self.gap,
+            };
+            start = self._end + gap.0;
+        }
+        Ok(())
+    }
+}
+
+fn default_duck_amount_db() -> f32 {
+    DEFAULT_DUCK_AMOUNT_DB
+}
+
+fn default_duck_attack_ms() -> f32 {
+    DEFAULT_DUCK_ATTACK_MS
+}
+
+fn default_duck_release_ms() -> f32 {
+    DEFAULT_DUCK_RELEASE_MS
+}
+
+/// Sidechain ducking of the beat bed (tones and noise) under the audio mixins.
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct DuckSpec {
+    /// How far to lower the bed, in dB
+    #[serde(default = "default_duck_amount_db")]
+    pub amount_db: f32,
+    /// How quickly the bed ducks once a mixin starts
+    #[serde(default = "default_duck_attack_ms")]
+    pub attack_ms: f32,
+    /// How quickly the bed comes back once the mixins go quiet
+    #[serde(default = "default_duck_release_ms")]
+    pub release_ms: f32,
+}
+
+impl Default for DuckSpec {
+    fn default() -> Self {
+        Self {
+            amount_db: DEFAULT_DUCK_AMOUNT_DB,
+            attack_ms: DEFAULT_DUCK_ATTACK_MS,
+            release_ms: DEFAULT_DUCK_RELEASE_MS,
+        }
+    }
+}
+
+fn default_compress_threshold_db() -> f32 {
+    DEFAULT_COMPRESS_THRESHOLD_DB
+}
+
+fn default_compress_ratio() -> f32 {
+    DEFAULT_COMPRESS_RATIO
+}
+
+fn default_compress_attack_ms() -> f32 {
+    DEFAULT_COMPRESS_ATTACK_MS
+}
+
+fn default_compress_release_ms() -> f32 {
+    DEFAULT_COMPRESS_RELEASE_MS
+}
+
+fn default_reverb_mix() -> f32 {
+    DEFAULT_REVERB_MIX
+}
+
+fn default_reverb_room() -> f32 {
+    DEFAULT_REVERB_ROOM
+}
+
+fn default_reverb_damping() -> f32 {
+    DEFAULT_REVERB_DAMPING
+}
+
+/// Gentle compression, to even out the loud and quiet words of a line.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub struct CompressSpec {
+    /// Level in dBFS above which it turns the speech down
+    #[serde(default = "default_compress_threshold_db")]
+    pub threshold_db: f32,
+    /// How much it's turned down, eg: 3 means 3dB over the threshold comes out 1dB over
+    #[serde(default = "default_compress_ratio")]
+    pub ratio: f32,
+    #[serde(default = "default_compress_attack_ms")]
+    pub attack_ms: f32,
+    #[serde(default = "default_compress_release_ms")]
+    pub release_ms: f32,
+}
+
+impl Default for CompressSpec {
+    fn default() -> Self {
+        Self {
+            threshold_db: DEFAULT_COMPRESS_THRESHOLD_DB,
+            ratio: DEFAULT_COMPRESS_RATIO,
+            attack_ms: DEFAULT_COMPRESS_ATTACK_MS,
+            release_ms: DEFAULT_COMPRESS_RELEASE_MS,
+        }
+    }
+}
+
+/// A simple algorithmic reverb, so the dry TTS voice sounds like it's in a room.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub struct ReverbSpec {
+    /// How much of the reverb is heard, from 0.0 (dry) to 1.0 (all reverb)
+    #[serde(default = "default_reverb_mix")]
+    pub mix: f32,
+    /// Room size from 0.0 to 1.0, where larger rings out longer
+    #[serde(default = "default_reverb_room")]
+    pub room: f32,
+    /// How quickly the high end of the reverb dies away, from 0.0 to 1.0
+    #[serde(default = "default_reverb_damping")]
+    pub damping: f32,
+}
+
+impl Default for ReverbSpec {
+    fn default() -> Self {
+        Self {
+            mix: DEFAULT_REVERB_MIX,
+            room: DEFAULT_REVERB_ROOM,
+            damping: DEFAULT_REVERB_DAMPING,
+        }
+    }
+}
+
+fn default_trim_threshold_db() -> f32 {
+    DEFAULT_TRIM_THRESHOLD_DB
+}
+
+fn default_trim_pad_ms() -> f32 {
+    DEFAULT_TRIM_PAD_MS
+}
+
+/// How the silence before and after a TTS clip is trimmed, so its `offset` is when the speech
+/// starts.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub struct TrimSpec {
+    /// Anything quieter than this, in dBFS, is silence
+    #[serde(default = "default_trim_threshold_db")]
+    pub threshold_db: f32,
+    /// Silence to keep either side of the speech, so it doesn't start or stop too abruptly
+    #[serde(default = "default_trim_pad_ms")]
+    pub pad_ms: f32,
+}
+
+impl Default for TrimSpec {
+    fn default() -> Self {
+        Self {
+            threshold_db: DEFAULT_TRIM_THRESHOLD_DB,
+            pad_ms: DEFAULT_TRIM_PAD_MS,
+        }
+    }
+}
+
+/// `trim: false` to keep the silence, or the trim settings.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum TrimSetting {
+    Enabled(bool),
+    Spec(TrimSpec),
+}
+
+impl Default for TrimSetting {
+    fn default() -> Self {
+        TrimSetting::Enabled(true)
+    }
+}
+
+impl TrimSetting {
+    pub fn spec(&self) -> Option<TrimSpec> {
+        match self {
+            TrimSetting::Enabled(true) => Some(TrimSpec::default()),
+            TrimSetting::Enabled(false) => None,
+            TrimSetting::Spec(spec) => Some(*spec),
+        }
+    }
+}
+
+/// Post-processing for generated speech, applied before it's mixed in. Each step is only done
+/// when it's set.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct SpeechFxSpec {
+    /// Integrated loudness to normalize to, in LUFS, eg: -18.0
+    #[serde(default)]
+    pub loudness: Option<f32>,
+    /// High-pass cutoff in Hz, eg: 80.0
+    #[serde(default)]
+    pub highpass: Option<f32>,
+    #[serde(default)]
+    pub compress: Option<CompressSpec>,
+    #[serde(default)]
+    pub reverb: Option<ReverbSpec>,
+}
+
+impl SpeechFxSpec {
+    pub fn is_empty(&self) -> bool {
+        *self == SpeechFxSpec::default()
+    }
+
+    /// Like `VoiceSpec::cache_key`, only the steps that were given.
+    fn cache_key(&self) -> String {
+        let mut parts: Vec<String> = Vec::new();
+        if let Some(v) = self.loudness {
+            parts.push(format!("loudness={}", v));
+        }
+        if let Some(v) = self.highpass {
+            parts.push(format!("highpass={}", v));
+        }
+        if let Some(c) = self.compress {
+            parts.push(format!(
+                "compress={},{},{},{}",
+                c.threshold_db, c.ratio, c.attack_ms, c.release_ms
+            ));
+        }
+        if let Some(r) = self.reverb {
+            parts.push(format!("reverb={},{},{}", r.mix, r.room, r.damping));
+        }
+        parts.join("::")
+    }
 }
 
 fn default_noise_gain() -> f32 {
@@ -209,6 +991,7 @@ fn default_noise_gain() -> f32 {
 pub enum AudioMixin {
     File(AudioSpec),
     TTS(TTSSpec),
+    Narration(NarrationSpec),
 }
 
 #[derive(Debug, Deserialize, Clone, Copy)]
@@ -232,6 +1015,11 @@ pub enum Segment {
         noise: Option<NoiseSpec>,
         #[serde(default)]
         audio: Vec<AudioMixin>,
+        #[serde(default)]
+        duck: Option<DuckSpec>,
+        /// Names the chapter marker at the start of the segment
+        #[serde(default)]
+        label: Option<String>,
     },
     /// Transition from -> to across duration, with an optional curve.
     Transition {
@@ -242,6 +1030,10 @@ pub enum Segment {
         curve: Option<Curve>,
         #[serde(default)]
         audio: Vec<AudioMixin>,
+        #[serde(default)]
+        duck: Option<DuckSpec>,
+        #[serde(default)]
+        label: Option<String>,
     },
 }
 
@@ -251,6 +1043,8 @@ pub enum Chunk {
         samples: usize,
         spec: ToneSpec,
         mixins: Vec<Mixin>,
+        duck: Option<DuckSpec>,
+        label: Option<String>,
     },
     Transition {
         samples: usize,
@@ -258,6 +1052,8 @@ pub enum Chunk {
         to: ToneSpec,
         curve: Curve,
         mixins: Vec<Mixin>,
+        duck: Option<DuckSpec>,
+        label: Option<String>,
     },
 }
 
@@ -268,6 +1064,240 @@ impl Chunk {
             Chunk::Transition { samples, .. } => *samples,
         }
     }
+    pub fn mixins(&self) -> &[Mixin] {
+        match self {
+            Chunk::Tone { mixins, .. } => mixins,
+            Chunk::Transition { mixins, .. } => mixins,
+        }
+    }
+    pub fn label(&self) -> Option<&str> {
+        match self {
+            Chunk::Tone { label, .. } => label.as_deref(),
+            Chunk::Transition { label, .. } => label.as_deref(),
+        }
+    }
+}
+
+/// A chapter marker at the start of each segment, named by its `label` or else its number. Empty
+/// segments are left out, since they'd share a position with the next one.
+pub fn chapters(chunks: &[Chunk]) -> Vec<Chapter> {
+    let mut chapters = Vec::new();
+    let mut start = 0;
+    for (i, chunk) in chunks.iter().enumerate() {
+        if chunk.samples() > 0 {
+            let name = match chunk.label() {
+                Some(label) => label.to_string(),
+                None => format!("Segment {}", i + 1),
+            };
+            chapters.push(Chapter { start, name });
+        }
+        start += chunk.samples();
+    }
+    chapters
+}
+
+/// A TTS line waiting to be generated, and where it is in the config for error messages.
+#[derive(Debug)]
+struct TtsJob {
+    segment: usize,
+    index: usize,
+    spec: TTSSpec,
+}
+
+/// Add a TTS line to the jobs to generate. Marked up lines add a job per clip instead, and are
+/// added to `scripts` to be assembled after.
+fn queue_tts(
+    job: TtsJob,
+    force: bool,
+    tts_jobs: &mut Vec<TtsJob>,
+    scripts: &mut Vec<(TtsJob, Vec<ScriptPart>)>,
+) -> Result<(), TtsError> {
+    let script = job
+        .spec
+        .script()
+        .map_err(|err| TtsError::new(job.segment, job.index, &job.spec.text, err))?;
+    match script {
+        None => tts_jobs.push(job),
+        Some(script) if force || !job.spec.is_cached() => {
+            for part in script.iter() {
+                if let ScriptPart::Clip(spec) = part {
+                    tts_jobs.push(TtsJob {
+                        segment: job.segment,
+                        index: job.index,
+                        spec: spec.as_ref().clone(),
+                    });
+                }
+            }
+            scripts.push((job, script));
+        }
+        Some(_) => debug!(
+            "{} already exists - skipping assembling marked up TTS",
+            job.spec._out_path.display()
+        ),
+    }
+    Ok(())
+}
+
+/// Generate every TTS clip that isn't cached yet with up to `jobs` piper processes at a time.
+/// Lines that share a cache path are only generated once. If any fail, no new lines are started
+/// and the error of the earliest failed line in the config is returned.
+fn generate_tts(
+    tts_jobs: &[TtsJob],
+    piper_bin: Option<&str>,
+    force: bool,
+    jobs: usize,
+) -> Result<(), TtsError> {
+    let mut seen: HashSet<&Path> = HashSet::new();
+    let todo: Vec<&TtsJob> = tts_jobs
+        .iter()
+        .filter(|job| seen.insert(&job.spec._out_path))
+        .filter(|job| {
+            let cached = job.spec.is_cached();
+            if cached && !force {
+                debug!(
+                    "{} already exists - skipping TTS generation and using old one. Delete it or update key if you want to regenerate.",
+                    job.spec._out_path.display()
+                );
+            }
+            force || !cached
+        })
+        .collect();
+    let batches = batch_jobs(&todo, jobs);
+    let threads = jobs.clamp(1, batches.len().max(1));
+    debug!(
+        "generating {} TTS clips in {} batches with {} jobs",
+        todo.len(),
+        batches.len(),
+        threads
+    );
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let errors: Mutex<Vec<(usize, TtsError)>> = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                while !failed.load(Ordering::Relaxed) {
+                    let Some(batch) = batches.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                        break;
+                    };
+                    let res = if let [i] = batch[..] {
+                        todo[i]
+                            .spec
+                            .generate(piper_bin, force)
+                            .map_err(|err| (i, err))
+                    } else {
+                        let specs: Vec<&TTSSpec> = batch.iter().map(|i| &todo[*i].spec).collect();
+                        TTSSpec::generate_batch(&specs, piper_bin)
+                            .map_err(|(line, err)| (batch[line], err))
+                    };
+                    if let Err((i, err)) = res {
+                        failed.store(true, Ordering::Relaxed);
+                        let job = todo[i];
+                        let err = TtsError::new(job.segment, job.index, &job.spec.text, err);
+                        errors.lock().unwrap().push((i, err));
+                    }
+                }
+            });
+        }
+    });
+
+    let mut errors = errors.into_inner().unwrap();
+    errors.sort_by_key(|(i, _)| *i);
+    match errors.into_iter().next() {
+        Some((_, err)) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Group the jobs that can share a piper process, as indexes into `todo`, so each model is only
+/// loaded once per process. Everything else is a batch of one. Each model's lines are then split
+/// into up to `jobs` batches, so a config with a single voice still runs in parallel.
+fn batch_jobs(todo: &[&TtsJob], jobs: usize) -> Vec<Vec<usize>> {
+    let mut batches: Vec<Vec<usize>> = Vec::new();
+    for (i, job) in todo.iter().enumerate() {
+        let key = job.spec.batch_key();
+        let batch = key.as_ref().and_then(|key| {
+            batches
+                .iter_mut()
+                .find(|batch| todo[batch[0]].spec.batch_key().as_ref() == Some(key))
+        });
+        match batch {
+            Some(batch) => batch.push(i),
+            None => batches.push(vec![i]),
+        }
+    }
+    batches
+        .into_iter()
+        .flat_map(|batch| {
+            let size = batch.len().div_ceil(jobs.clamp(1, batch.len()));
+            batch
+                .chunks(size)
+                .map(|chunk| chunk.to_vec())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Turn the initialized audio specs of a segment into mixins ready to render.
+fn build_mixins(segment: usize, audio: &[AudioMixin], audio_dir: &Path) -> Vec<Mixin> {
+    let mut mixins: Vec<Mixin> = Vec::new();
+    for (index, mixin_spec) in audio.iter().enumerate() {
+        let specs: Vec<Mixin> = match mixin_spec {
+            AudioMixin::File(audio_spec) => vec![Mixin::from(audio_spec.clone())],
+            AudioMixin::TTS(tts_spec) => vec![Mixin::from(tts_spec.clone())],
+            AudioMixin::Narration(narration) => {
+                narration._lines.iter().cloned().map(Mixin::from).collect()
+            }
+        };
+        for mut mixin in specs {
+            mixin.segment = segment;
+            mixin.index = index;
+            mixin.cache_dir = Some(audio_dir.to_path_buf());
+            mixins.push(mixin);
+        }
+    }
+    mixins
+}
+
+/// The length of a segment in samples, made longer to fit any narration with `extend: true`.
+fn extend_to_fit(segment: usize, samples: usize, audio: &[AudioMixin], sr: u32) -> usize {
+    let needed = audio
+        .iter()
+        .filter_map(|mixin_spec| match mixin_spec {
+            AudioMixin::Narration(narration) if narration.extend => {
+                Some(secs_to_samples(narration._end, sr))
+            }
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+    if needed > samples {
+        info!(
+            "extending segment {} from {:.2}s to {:.2}s to fit its narration",
+            segment,
+            samples as f32 / sr as f32,
+            needed as f32 / sr as f32
+        );
+        needed
+    } else {
+        samples
+    }
+}
+
+impl Segment {
+    pub fn audio(&self) -> &[AudioMixin] {
+        match self {
+            Segment::Tone { audio, .. } => audio,
+            Segment::Transition { audio, .. } => audio,
+        }
+    }
+    pub fn audio_mut(&mut self) -> &mut [AudioMixin] {
+        match self {
+            Segment::Tone { audio, .. } => audio,
+            Segment::Transition { audio, .. } => audio,
+        }
+    }
 }
 
 impl Config {
@@ -319,6 +1349,9 @@ impl Config {
                 sysconfig::get_models_dir().expect("Failed to get default system models dir");
         }
 
+        let config_dir = config_path.parent().unwrap_or(Path::new(""));
+        self._lexicon_file = resolve_relative(config_dir, &self.lexicon_file);
+
         info!(
             "system audio directory normalized to: {}",
             self._audio_dir.display()
@@ -344,19 +1377,190 @@ impl Config {
     pub fn get_fade_ms(&self) -> f32 {
         self.fade_ms.unwrap_or(DEFAULT_FADE_MS).max(0.0)
     }
+    /// The lexicon file's words, with the config's own `lexicon` over them.
+    pub fn get_lexicon(&self) -> Result<Option<Arc<Lexicon>>, Box<dyn std::error::Error>> {
+        let mut words = match &self._lexicon_file {
+            Some(path) => Lexicon::load_file(path)?,
+            None => BTreeMap::new(),
+        };
+        words.extend(self.lexicon.clone());
+        Ok(Lexicon::new(&words).map(Arc::new))
+    }
+
+    /// Initialize every audio spec, and find the TTS to generate: plain lines as jobs, and marked
+    /// up lines with their clips queued as jobs, to be assembled once they're generated.
+    #[allow(clippy::type_complexity)]
+    fn init_specs(
+        &mut self,
+        force: bool,
+    ) -> Result<(Vec<TtsJob>, Vec<(TtsJob, Vec<ScriptPart>)>), Box<dyn std::error::Error>> {
+        let default_overflow = self.overflow;
+        let defaults = LineDefaults {
+            voice: self.voice.clone(),
+            overflow: self.overflow,
+            speech_fx: self.speech_fx.clone(),
+            trim: self.trim,
+            lexicon: self.get_lexicon()?,
+        };
+        let model_dir = &self._model_dir;
+        let audio_dir = &self._audio_dir;
+
+        let mut tts_jobs: Vec<TtsJob> = Vec::new();
+        let mut scripts: Vec<(TtsJob, Vec<ScriptPart>)> = Vec::new();
+        for (seg_idx, seg) in self.segments.iter_mut().enumerate() {
+            for (index, mixin_spec) in seg.audio_mut().iter_mut().enumerate() {
+                match mixin_spec {
+                    AudioMixin::File(audio_spec) => {
+                        debug!("found audio spec {:?}", audio_spec);
+                        audio_spec.init_paths(audio_dir)?;
+                        audio_spec.overflow = audio_spec.overflow.or(default_overflow);
+                    }
+                    AudioMixin::TTS(tts_spec) => {
+                        debug!("found tts spec {:?}", tts_spec);
+                        tts_spec.apply_defaults(&defaults);
+                        tts_spec.init_paths(audio_dir, model_dir)?;
+                        let job = TtsJob {
+                            segment: seg_idx,
+                            index,
+                            spec: tts_spec.clone(),
+                        };
+                        queue_tts(job, force, &mut tts_jobs, &mut scripts)?;
+                    }
+                    AudioMixin::Narration(narration) => {
+                        debug!("found narration spec {:?}", narration);
+                        narration.init_lines(audio_dir, model_dir, &defaults)?;
+                        for spec in narration._lines.iter() {
+                            let job = TtsJob {
+                                segment: seg_idx,
+                                index,
+                                spec: spec.clone(),
+                            };
+                            queue_tts(job, force, &mut tts_jobs, &mut scripts)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok((tts_jobs, scripts))
+    }
+
+    /// Check every model and audio file the initialized specs use is there.
+    fn check_files(&self) -> std::io::Result<()> {
+        for seg in self.segments.iter() {
+            for mixin_spec in seg.audio().iter() {
+                match mixin_spec {
+                    AudioMixin::File(audio_spec) => check_exists("audio file", &audio_spec._path)?,
+                    AudioMixin::TTS(spec) => spec.check_files()?,
+                    AudioMixin::Narration(narration) => {
+                        for spec in narration._lines.iter() {
+                            spec.check_files()?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The audio dir, and every generated file in it this config uses: its TTS clips, including
+    /// the pieces of marked up lines, and decoded copies of its mixins that exist. This only works
+    /// out paths, so it doesn't need the models or mixins to exist, and doesn't create anything.
+    pub fn referenced_cache_files(
+        mut self,
+    ) -> Result<(PathBuf, HashSet<PathBuf>), Box<dyn std::error::Error>> {
+        let sr = self.get_sample_rate();
+        let (tts_jobs, scripts) = self.init_specs(true)?;
+        let mut referenced: HashSet<PathBuf> = HashSet::new();
+        for job in tts_jobs.iter().chain(scripts.iter().map(|(job, _)| job)) {
+            referenced.insert(job.spec._out_path.clone());
+            referenced.extend(job.spec._fx_path.clone());
+        }
+        let mut sources: Vec<PathBuf> = referenced.iter().cloned().collect();
+        for seg in self.segments.iter() {
+            for mixin_spec in seg.audio().iter() {
+                if let AudioMixin::File(audio_spec) = mixin_spec {
+                    sources.push(audio_spec._path.clone());
+                }
+            }
+        }
+        for source in sources.iter().filter(|path| path.exists()) {
+            referenced.insert(self._audio_dir.join(mixin::decoded_name(source, sr)?));
+        }
+        Ok((self._audio_dir, referenced))
+    }
 
-    /// Build a flat plan of samples to render by iterating segments
+    /// Build a flat plan of samples to render by iterating segments. TTS is generated first with
+    /// `piper_bin`, `jobs` clips at a time, and regenerated even if cached when `force` is set.
     pub fn create_chunks(
         mut self,
         piper_bin: Option<&str>,
         force: bool,
-    ) -> Result<Vec<Chunk>, std::io::Error> {
+        jobs: usize,
+    ) -> Result<Vec<Chunk>, Box<dyn std::error::Error>> {
         let mut chunks: Vec<Chunk> = Vec::new();
         let sr = self.get_sample_rate();
-        let model_dir = self._model_dir;
-        let audio_dir = self._audio_dir;
-        std::fs::create_dir_all(&audio_dir)?;
-        for seg in self.segments.iter_mut() {
+        let default_duck = self.duck;
+        let (tts_jobs, scripts) = self.init_specs(force)?;
+        self.check_files()?;
+        std::fs::create_dir_all(&self._audio_dir)?;
+        let audio_dir = std::mem::take(&mut self._audio_dir);
+        generate_tts(&tts_jobs, piper_bin, force, jobs)?;
+        for (job, script) in scripts.iter() {
+            job.spec
+                .assemble(script)
+                .map_err(|err| TtsError::new(job.segment, job.index, &job.spec.text, err))?;
+        }
+        let mut manifest = Manifest::load(&audio_dir);
+        for job in tts_jobs.iter() {
+            manifest.record(&job.spec);
+        }
+        for seg in self.segments.iter() {
+            for mixin_spec in seg.audio().iter() {
+                match mixin_spec {
+                    AudioMixin::TTS(spec) => manifest.record(spec),
+                    AudioMixin::Narration(narration) => narration
+                        ._lines
+                        .iter()
+                        .for_each(|spec| manifest.record(spec)),
+                    AudioMixin::File(_) => {}
+                }
+            }
+        }
+        if let Err(err) = manifest.save(&audio_dir) {
+            log::warn!(
+                "failed to save the cache manifest in {:?}: {}",
+                audio_dir,
+                err
+            );
+        }
+
+        for (seg_idx, seg) in self.segments.iter().enumerate() {
+            for (index, mixin_spec) in seg.audio().iter().enumerate() {
+                let specs: Vec<&TTSSpec> = match mixin_spec {
+                    AudioMixin::TTS(spec) => vec![spec],
+                    AudioMixin::Narration(narration) => narration._lines.iter().collect(),
+                    AudioMixin::File(_) => continue,
+                };
+                for spec in specs {
+                    spec.process_clip(force)
+                        .map_err(|err| TtsError::new(seg_idx, index, &spec.text, err))?;
+                }
+            }
+        }
+
+        // Now the clips exist, their lengths give the offsets of narration lines.
+        for (seg_idx, seg) in self.segments.iter_mut().enumerate() {
+            for (index, mixin_spec) in seg.audio_mut().iter_mut().enumerate() {
+                if let AudioMixin::Narration(narration) = mixin_spec {
+                    narration.sequence().map_err(|(line, err)| {
+                        let text = narration.lines[line].text();
+                        TtsError::new(seg_idx, index, text, err)
+                    })?;
+                }
+            }
+        }
+
+        for (seg_idx, seg) in self.segments.iter().enumerate() {
             match seg {
                 Segment::Tone {
                     dur,
@@ -365,24 +1569,11 @@ impl Config {
                     hz,
                     noise,
                     audio,
+                    duck,
+                    label,
                 } => {
-                    let total = secs_to_samples(dur.0, sr);
-                    let mut mixins: Vec<Mixin> = Vec::new();
-                    for mixin_spec in audio.iter_mut() {
-                        match mixin_spec {
-                            AudioMixin::File(audio_spec) => {
-                                debug!("found audio spec {:?}", audio_spec);
-                                audio_spec.init_paths(&audio_dir)?;
-                                mixins.push(Mixin::from(audio_spec.clone()))
-                            }
-                            AudioMixin::TTS(tts_spec) => {
-                                debug!("found tts spec {:?}", tts_spec);
-                                tts_spec.init_paths(&audio_dir, &model_dir)?;
-                                tts_spec.generate(piper_bin, force)?;
-                                mixins.push(Mixin::from(tts_spec.clone()))
-                            }
-                        }
-                    }
+                    let total = extend_to_fit(seg_idx, secs_to_samples(dur.0, sr), audio, sr);
+                    let mixins = build_mixins(seg_idx, audio, &audio_dir);
                     chunks.push(Chunk::Tone {
                         samples: total,
                         spec: ToneSpec {
@@ -392,6 +1583,8 @@ impl Config {
                             noise: *noise,
                         },
                         mixins,
+                        duck: duck.or(default_duck),
+                        label: label.clone(),
                     });
                 }
                 Segment::Transition {
@@ -400,37 +1593,471 @@ impl Config {
                     to,
                     curve,
                     audio,
+                    duck,
+                    label,
                 } => {
-                    let total = secs_to_samples(dur.0, sr);
-                    let mut mixins: Vec<Mixin> = Vec::new();
-                    for mixin_spec in audio.iter_mut() {
-                        match mixin_spec {
-                            AudioMixin::File(audio_spec) => {
-                                debug!("found audio spec {:?}", audio_spec);
-                                audio_spec.init_paths(&audio_dir)?;
-                                mixins.push(Mixin::from(audio_spec.clone()))
-                            }
-                            AudioMixin::TTS(tts_spec) => {
-                                debug!("found tts spec {:?}", tts_spec);
-                                tts_spec.init_paths(&audio_dir, &model_dir)?;
-                                tts_spec.generate(piper_bin, force)?;
-                                mixins.push(Mixin::from(tts_spec.clone()))
-                            }
-                        }
-                    }
+                    let total = extend_to_fit(seg_idx, secs_to_samples(dur.0, sr), audio, sr);
+                    let mixins = build_mixins(seg_idx, audio, &audio_dir);
                     chunks.push(Chunk::Transition {
                         samples: total,
                         from: *from,
                         to: *to,
                         curve: curve.unwrap_or(Curve::Linear),
                         mixins,
+                        duck: duck.or(default_duck),
+                        label: label.clone(),
                     });
                 }
             }
         }
         for (i, chunk) in chunks.iter().enumerate() {
             debug!("Chunk {}: {:?}", i, chunk);
+            for mixin in chunk.mixins() {
+                let Some(text) = &mixin.text else {
+                    continue;
+                };
+                match mixin.duration() {
+                    Ok(secs) => debug!(
+                        "  mixin {} at {:.2}s for {:.2}s: {:?}",
+                        mixin.index,
+                        mixin.offset,
+                        secs,
+                        tts::snippet(text)
+                    ),
+                    Err(err) => debug!("  mixin {} has no clip yet: {}", mixin.index, err),
+                }
+            }
         }
         Ok(chunks)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tts_job(segment: usize, dir: &Path, name: &str) -> TtsJob {
+        let mut spec: TTSSpec =
+            serde_yaml::from_str(&format!("model: m.onnx\ntext: {}", name)).unwrap();
+        spec._out_path = dir.join(name);
+        TtsJob {
+            segment,
+            index: 0,
+            spec,
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_generate_tts_reports_earliest_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        // Different voice settings keep them in separate batches, so they run in parallel.
+        let jobs: Vec<TtsJob> = (0..6)
+            .map(|i| {
+                let mut job = tts_job(i, dir.path(), &format!("line{}", i));
+                job.spec.voice.length_scale = Some(1.0 + i as f32);
+                job
+            })
+            .collect();
+        let err = generate_tts(&jobs, Some("false"), false, 4).unwrap_err();
+        assert_eq!(err.segment, 0);
+        assert_eq!(err.snippet, "line0");
+    }
+
+    #[test]
+    fn test_generate_tts_skips_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let jobs = vec![tts_job(0, dir.path(), "cached")];
+        std::fs::write(dir.path().join("cached"), b"RIFF").unwrap();
+        generate_tts(&jobs, Some("/nonexistent/piper"), false, 4).unwrap();
+    }
+
+    #[test]
+    fn test_batch_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut jobs: Vec<TtsJob> = (0..5)
+            .map(|i| tts_job(0, dir.path(), &format!("line{}", i)))
+            .collect();
+        // The speaker can differ within a batch, but not other voice settings or the engine.
+        jobs[1].spec.voice.speaker = Some(2);
+        jobs[2].spec.voice.length_scale = Some(1.5);
+        jobs[3].spec.engine.engine = Some(Engine::EspeakNg);
+        let todo: Vec<&TtsJob> = jobs.iter().collect();
+        assert_eq!(batch_jobs(&todo, 1), vec![vec![0, 1, 4], vec![2], vec![3]]);
+    }
+
+    #[test]
+    fn test_batch_jobs_splits_for_parallel_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let jobs: Vec<TtsJob> = (0..7)
+            .map(|i| tts_job(0, dir.path(), &format!("line{}", i)))
+            .collect();
+        let todo: Vec<&TtsJob> = jobs.iter().collect();
+        // One model, so one process unless there are jobs to spread it over.
+        assert_eq!(batch_jobs(&todo, 1), vec![vec![0, 1, 2, 3, 4, 5, 6]]);
+        assert_eq!(
+            batch_jobs(&todo, 3),
+            vec![vec![0, 1, 2], vec![3, 4, 5], vec![6]]
+        );
+        // Never more batches than lines
+        assert_eq!(batch_jobs(&todo[..2], 8), vec![vec![0], vec![1]]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_generate_tts_batch_failure_names_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let jobs: Vec<TtsJob> = (0..3)
+            .map(|i| tts_job(i, dir.path(), &format!("line{}", i)))
+            .collect();
+        // The first line is already cached, so the batch starts at the second, which `true`
+        // leaves missing.
+        std::fs::write(dir.path().join("line0"), b"RIFF").unwrap();
+        let err = generate_tts(&jobs, Some("true"), false, 4).unwrap_err();
+        assert_eq!(err.segment, 1);
+        assert!(!dir.path().join("line1").exists());
+    }
+
+    #[test]
+    fn test_voice_settings_change_key() {
+        let mut spec: TTSSpec = serde_yaml::from_str("model: m.onnx\ntext: hello").unwrap();
+        let plain = spec.get_key();
+        spec.voice = VoiceSpec {
+            length_scale: Some(1.2),
+            ..Default::default()
+        };
+        let slower = spec.get_key();
+        assert_ne!(plain, slower);
+        spec.voice.speaker = Some(3);
+        assert_ne!(slower, spec.get_key());
+    }
+
+    #[test]
+    fn test_engine_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let tts = |yaml: &str| -> std::io::Result<TTSSpec> {
+            let mut spec: TTSSpec = serde_yaml::from_str(yaml).unwrap();
+            spec.init_paths(dir.path(), dir.path())?;
+            Ok(spec)
+        };
+        let espeak = tts("engine: espeak-ng\nvoice_name: en-us\ntext: hello").unwrap();
+        assert_eq!(espeak.engine.get_engine(), Engine::EspeakNg);
+        let other_voice = tts("engine: espeak-ng\nvoice_name: en-gb\ntext: hello").unwrap();
+        assert_ne!(espeak._out_path, other_voice._out_path);
+
+        let cmd = tts("engine: command\ncmd: mytts --out {out}\ntext: hello").unwrap();
+        assert_ne!(espeak._out_path, cmd._out_path);
+
+        // Piper needs its model, and a command its template.
+        assert!(tts("text: hello").is_err());
+        assert!(tts("engine: command\ntext: hello").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_generate_with_command_engine() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spec: TTSSpec =
+            serde_yaml::from_str("engine: command\ncmd: tee {out}\ntext: hello").unwrap();
+        spec.init_paths(dir.path(), dir.path()).unwrap();
+        spec.generate(None, false).unwrap();
+        assert_eq!(std::fs::read_to_string(&spec._out_path).unwrap(), "hello");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_lexicon_changes_spoken_text_and_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let words: BTreeMap<String, String> = [("theta".to_string(), "thayta".to_string())]
+            .into_iter()
+            .collect();
+        let lexicon = Lexicon::new(&words).map(Arc::new);
+        let spec = |text: &str, lexicon: &Option<Arc<Lexicon>>| {
+            let mut spec: TTSSpec = serde_yaml::from_str(&format!(
+                "engine: command\ncmd: tee {{out}}\ntext: {}",
+                text
+            ))
+            .unwrap();
+            spec._lexicon = lexicon.clone();
+            spec.init_paths(dir.path(), dir.path()).unwrap();
+            spec
+        };
+        // Lines without any of the words keep their keys.
+        assert_eq!(
+            spec("Relax.", &None)._out_path,
+            spec("Relax.", &lexicon)._out_path
+        );
+        let fixed = spec("Theta waves.", &lexicon);
+        assert_ne!(spec("Theta waves.", &None)._out_path, fixed._out_path);
+
+        fixed.generate(None, false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&fixed._out_path).unwrap(),
+            "thayta waves."
+        );
+    }
+
+    #[test]
+    fn test_lexicon_file_and_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("words.yaml"),
+            "theta: thayta\nbinaural: bye-nawral\n",
+        )
+        .unwrap();
+        let config_path = dir.path().join("session.yaml");
+        let mut cfg: Config = serde_yaml::from_str(&format!(
+            "audio_dir: {}\nlexicon_file: words.yaml\nlexicon:\n  theta: thee-ta\nsegments: []",
+            dir.path().display()
+        ))
+        .unwrap();
+        cfg.normalize_paths(&config_path);
+        let lexicon = cfg.get_lexicon().unwrap().unwrap();
+        assert_eq!(lexicon.apply("theta binaural"), "thee-ta bye-nawral");
+    }
+
+    #[test]
+    fn test_referenced_cache_files_touches_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let audio_dir = dir.path().join("audio");
+        let mut cfg: Config = serde_yaml::from_str(&format!(
+            "audio_dir: {}\nmodel_dir: {}\nsegments:\n  - type: tone\n    dur: 1s\n    carrier: 200\n    hz: 7\n    audio:\n      - {{type: tts, model: gone.onnx, text: Relax.}}\n      - {{type: file, path: gone.wav}}\n",
+            audio_dir.display(),
+            dir.path().join("models").display()
+        ))
+        .unwrap();
+        cfg.normalize_paths(&dir.path().join("session.yaml"));
+        // Neither the model nor the mixin exist, and nor does the audio dir.
+        let (found_dir, referenced) = cfg.referenced_cache_files().unwrap();
+        assert_eq!(found_dir, audio_dir);
+        assert!(!audio_dir.exists());
+        assert_eq!(referenced.len(), 2, "{:?}", referenced);
+        assert!(referenced.iter().all(|p| p.starts_with(&audio_dir)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_key_is_stable_through_symlinked_model_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let models = dir.path().join("models");
+        std::fs::create_dir(&models).unwrap();
+        std::fs::write(models.join("voice.onnx"), b"model").unwrap();
+        std::fs::write(models.join("voice.onnx.json"), b"{}").unwrap();
+        let linked = dir.path().join("linked");
+        std::os::unix::fs::symlink(&models, &linked).unwrap();
+
+        let out_path = |model_dir: &Path| {
+            let mut spec: TTSSpec =
+                serde_yaml::from_str("model: voice.onnx\ntext: Relax.").unwrap();
+            spec.init_paths(dir.path(), model_dir).unwrap();
+            spec._out_path
+        };
+        assert_eq!(out_path(&linked), out_path(&models));
+    }
+
+    #[test]
+    fn test_speech_fx() {
+        let dir = tempfile::tempdir().unwrap();
+        let tts = |yaml: &str| {
+            let mut spec: TTSSpec = serde_yaml::from_str(yaml).unwrap();
+            spec.init_paths(dir.path(), dir.path()).unwrap();
+            spec
+        };
+        let dry = tts("engine: espeak-ng\ntext: hello\ntrim: false");
+        assert_eq!(dry.clip_path(), dry._out_path);
+        // Nothing set is the same as no fx.
+        assert!(
+            tts("engine: espeak-ng\ntext: hello\ntrim: false\nspeech_fx: {}")
+                ._fx_path
+                .is_none()
+        );
+
+        let wet = tts("engine: espeak-ng\ntext: hello\nspeech_fx:\n  reverb: {}\n  loudness: -20");
+        // The generated clip is shared, only the processed one differs.
+        assert_eq!(dry._out_path, wet._out_path);
+        let fx_path = wet._fx_path.clone().unwrap();
+        assert!(
+            fx_path
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with(FX_PREFIX)
+        );
+        assert_eq!(wet.clip_path(), fx_path);
+        let louder =
+            tts("engine: espeak-ng\ntext: hello\nspeech_fx:\n  reverb: {}\n  loudness: -16");
+        assert_ne!(louder._fx_path, wet._fx_path);
+
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 16000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&wet._out_path, spec).unwrap();
+        for n in 0..16000 {
+            let s = (n as f32 * 440.0 * std::f32::consts::TAU / 16000.0).sin() * 3000.0;
+            writer.write_sample(s as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        wet.process_clip(false).unwrap();
+        let reader = hound::WavReader::open(&fx_path).unwrap();
+        assert_eq!(reader.spec().sample_format, hound::SampleFormat::Float);
+        // The reverb tail makes it longer.
+        assert!(reader.duration() > 16000);
+    }
+
+    #[test]
+    fn test_trim_silence() {
+        let dir = tempfile::tempdir().unwrap();
+        let tts = |yaml: &str| {
+            let mut spec: TTSSpec = serde_yaml::from_str(yaml).unwrap();
+            spec.init_paths(dir.path(), dir.path()).unwrap();
+            spec
+        };
+        // Trimmed by default, to a clip of its own.
+        let trimmed = tts("engine: espeak-ng\ntext: hello");
+        assert_eq!(trimmed.trim_spec(), Some(TrimSpec::default()));
+        let fx_path = trimmed._fx_path.clone().unwrap();
+        let padded = tts("engine: espeak-ng\ntext: hello\ntrim:\n  pad_ms: 100");
+        assert_eq!(padded._out_path, trimmed._out_path);
+        assert_ne!(padded._fx_path.unwrap(), fx_path);
+
+        // Half a second of silence either side of half a second of sound.
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 1000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&trimmed._out_path, spec).unwrap();
+        for n in 0..1500 {
+            writer
+                .write_sample(if (500..1000).contains(&n) {
+                    3000_i16
+                } else {
+                    0
+                })
+                .unwrap();
+        }
+        writer.finalize().unwrap();
+        trimmed.process_clip(false).unwrap();
+        // What's left is the sound and 20ms of padding either side.
+        assert_eq!(hound::WavReader::open(&fx_path).unwrap().duration(), 540);
+    }
+
+    #[test]
+    fn test_voice_defaults_and_overrides() {
+        let spec: TTSSpec =
+            serde_yaml::from_str("model: m.onnx\ntext: hello\nlength_scale: 1.5\nspeaker: 2")
+                .unwrap();
+        let defaults = VoiceSpec {
+            length_scale: Some(1.1),
+            sentence_silence: Some(0.5),
+            ..Default::default()
+        };
+        let voice = spec.voice.or(&defaults);
+        assert_eq!(voice.length_scale, Some(1.5));
+        assert_eq!(voice.speaker, Some(2));
+        assert_eq!(voice.sentence_silence, Some(0.5));
+        assert_eq!(voice.noise_scale, None);
+    }
+
+    #[test]
+    fn test_narration_lines() {
+        let spec: NarrationSpec = serde_yaml::from_str(
+            "model: m.onnx\ngap: 2s\nlines:\n  - Hello.\n  - text: Breathe.\n    gap: 5s\n",
+        )
+        .unwrap();
+        assert_eq!(spec.lines.len(), 2);
+        assert_eq!(spec.lines[0].text(), "Hello.");
+        assert!(matches!(spec.lines[1], NarrationLine::Line { gap: Some(g), .. } if g.0 == 5.0));
+        assert_eq!(spec.gap.0, 2.0);
+    }
+
+    #[test]
+    fn test_narration_sequence_and_extend() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut narration: NarrationSpec = serde_yaml::from_str(
+            "model: m.onnx\noffset: 1s\ngap: 2s\nextend: true\nlines:\n  - text: One.\n    gap: 0.5s\n  - Two.\n  - Three.\n",
+        )
+        .unwrap();
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        for (i, line) in narration.lines.iter().enumerate() {
+            let mut tts: TTSSpec =
+                serde_yaml::from_str(&format!("model: m.onnx\ntext: {}", line.text())).unwrap();
+            tts._out_path = dir.path().join(format!("line{}", i));
+            // Each line lasts 1.5 seconds.
+            let mut writer = hound::WavWriter::create(&tts._out_path, spec).unwrap();
+            for _ in 0..150 {
+                writer.write_sample(0_i16).unwrap();
+            }
+            writer.finalize().unwrap();
+            narration._lines.push(tts);
+        }
+        narration.sequence().unwrap();
+        let offsets: Vec<f32> = narration._lines.iter().map(|l| l.offset.0).collect();
+        assert_eq!(offsets, vec![1.0, 3.0, 6.5]);
+        assert_eq!(narration._end, 8.0);
+
+        // A clip that can't be read is reported against its own line.
+        let mut broken = narration.clone();
+        std::fs::write(&broken._lines[1]._out_path, b"not a wav").unwrap();
+        let (line, _) = broken.sequence().unwrap_err();
+        assert_eq!(broken.lines[line].text(), "Two.");
+
+        let audio = vec![AudioMixin::Narration(narration)];
+        assert_eq!(extend_to_fit(0, 500, &audio, 100), 800);
+        assert_eq!(extend_to_fit(0, 1000, &audio, 100), 1000);
+    }
+
+    #[test]
+    fn test_chapters() {
+        let segments: Vec<Segment> = serde_yaml::from_str(
+            "- {type: tone, dur: 1s, carrier: 200, hz: 7, label: Induction}\n- {type: tone, dur: 0s, carrier: 200, hz: 7}\n- {type: transition, dur: 2s, from: {carrier: 200, hz: 7}, to: {carrier: 200, hz: 4}}\n",
+        )
+        .unwrap();
+        let chunks: Vec<Chunk> = segments
+            .iter()
+            .map(|seg| {
+                let (samples, label) = match seg {
+                    Segment::Tone { dur, label, .. } | Segment::Transition { dur, label, .. } => {
+                        (secs_to_samples(dur.0, 100), label.clone())
+                    }
+                };
+                Chunk::Tone {
+                    samples,
+                    spec: ToneSpec {
+                        carrier: 200.0,
+                        hz: 7.0,
+                        gain: 0.1,
+                        noise: None,
+                    },
+                    mixins: vec![],
+                    duck: None,
+                    label,
+                }
+            })
+            .collect();
+        // The empty segment has no marker, but still counts toward the names of later ones.
+        let chapters = chapters(&chunks);
+        assert_eq!(
+            chapters,
+            vec![
+                Chapter {
+                    start: 0,
+                    name: "Induction".to_string()
+                },
+                Chapter {
+                    start: 100,
+                    name: "Segment 3".to_string()
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,134 @@
+/// Sidechain ducking: lowers the tone/noise bed while a mixin (eg: TTS narration) is playing.
+use crate::config::DuckSpec;
+
+/// Anything quieter than this in the mixin bus (-60 dB) is considered silence.
+const DUCK_THRESHOLD: f32 = 0.001;
+/// How quickly the sidechain envelope falls back after a peak. Short, since the release time
+/// handles smoothing between words.
+const ENVELOPE_MS: f32 = 20.0;
+
+/// One-pole smoothing coefficient for a time constant in milliseconds.
+fn coeff(ms: f32, sample_rate: u32) -> f32 {
+    if ms <= 0.0 {
+        return 0.0;
+    }
+    (-1.0 / (ms / 1000.0 * sample_rate as f32)).exp()
+}
+
+/// Converts decibels to a linear gain, eg: -6 dB is roughly 0.5.
+#[inline]
+pub fn db_to_gain(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0)
+}
+
+#[derive(Debug, Clone)]
+pub struct Ducker {
+    sample_rate: u32,
+    enabled: bool,
+    floor: f32,
+    attack: f32,
+    release: f32,
+    env_decay: f32,
+    env: f32,
+    gain: f32,
+}
+
+impl Ducker {
+    pub fn new(sample_rate: u32) -> Self {
+        let mut ducker = Self {
+            sample_rate,
+            enabled: false,
+            floor: 1.0,
+            attack: 0.0,
+            release: 0.0,
+            env_decay: coeff(ENVELOPE_MS, sample_rate),
+            env: 0.0,
+            gain: 1.0,
+        };
+        ducker.set_spec(None);
+        ducker
+    }
+
+    /// Switch to the ducking settings of the next chunk. The current gain and envelope carry over
+    /// so there is no jump at segment boundaries, and with `None` the bed releases back to unity.
+    pub fn set_spec(&mut self, spec: Option<DuckSpec>) {
+        let spec_or_default = spec.unwrap_or_default();
+        self.enabled = spec.is_some();
+        self.floor = db_to_gain(-spec_or_default.amount_db.abs());
+        self.attack = coeff(spec_or_default.attack_ms, self.sample_rate);
+        self.release = coeff(spec_or_default.release_ms, self.sample_rate);
+    }
+
+    /// Given the current sample of the mixin bus, return the gain to apply to the bed.
+    pub fn next_gain(&mut self, sidechain: f32) -> f32 {
+        let level = sidechain.abs();
+        self.env = if level > self.env {
+            level
+        } else {
+            self.env * self.env_decay
+        };
+        let target = if self.enabled && self.env > DUCK_THRESHOLD {
+            self.floor
+        } else {
+            1.0
+        };
+        let c = if target < self.gain {
+            self.attack
+        } else {
+            self.release
+        };
+        self.gain = target + (self.gain - target) * c;
+        self.gain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(amount_db: f32) -> DuckSpec {
+        DuckSpec {
+            amount_db,
+            attack_ms: 10.0,
+            release_ms: 100.0,
+        }
+    }
+
+    #[test]
+    fn test_db_to_gain() {
+        assert!((db_to_gain(0.0) - 1.0).abs() < 1e-6);
+        assert!((db_to_gain(-20.0) - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_no_spec_is_unity() {
+        let mut ducker = Ducker::new(1000);
+        for _ in 0..100 {
+            assert_eq!(ducker.next_gain(0.8), 1.0);
+        }
+    }
+
+    #[test]
+    fn test_ducks_while_active_and_releases_after() {
+        let mut ducker = Ducker::new(1000);
+        ducker.set_spec(Some(spec(20.0)));
+        let mut gain = 1.0;
+        for _ in 0..200 {
+            gain = ducker.next_gain(0.5);
+        }
+        assert!((gain - 0.1).abs() < 1e-3, "gain was {}", gain);
+        for _ in 0..2000 {
+            gain = ducker.next_gain(0.0);
+        }
+        assert!((gain - 1.0).abs() < 1e-3, "gain was {}", gain);
+    }
+
+    #[test]
+    fn test_silence_does_not_duck() {
+        let mut ducker = Ducker::new(1000);
+        ducker.set_spec(Some(spec(12.0)));
+        for _ in 0..100 {
+            assert_eq!(ducker.next_gain(0.0), 1.0);
+        }
+    }
+}
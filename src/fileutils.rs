@@ -1,4 +1,7 @@
-use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Component, Path, PathBuf};
 
 fn expand_tilde(p: &Path) -> PathBuf {
     if let Some(s) = p.to_str()
@@ -33,3 +36,56 @@ pub fn to_absolute(dir: &Path) -> Option<PathBuf> {
         None
     }
 }
+
+/// An absolute path with any `.` and `..` worked out, without touching the filesystem. Unlike
+/// `fs::canonicalize` it works for files that don't exist, but doesn't follow symlinks.
+pub fn normalize(path: &Path) -> PathBuf {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut out = PathBuf::new();
+    for part in path.components() {
+        match part {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// The canonical path when the file exists, so paths through a symlink resolve the same as the
+/// target. When it doesn't, eg: pruning with a model that's since been removed, the `normalize`d
+/// path instead.
+pub fn resolve(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| normalize(path))
+}
+
+/// Hex encoded sha256 of a file's contents, read in blocks so large files aren't loaded at once.
+pub fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize(Path::new("/models/./en/../voice.onnx")),
+            PathBuf::from("/models/voice.onnx")
+        );
+        assert!(normalize(Path::new("voice.onnx")).is_absolute());
+    }
+}
@@ -0,0 +1,88 @@
+/// Pronunciation fixes for TTS text, eg: "theta" -> "thayta", so a word the voice gets wrong is
+/// fixed once rather than misspelled in every line. A replacement can also be piper phonemes, eg:
+/// "[[ θˈeɪɾə ]]".
+use regex::{Captures, Regex};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+#[derive(Debug, Clone)]
+pub struct Lexicon {
+    /// Replacements by lowercased word
+    entries: HashMap<String, String>,
+    /// Matches any of the words, whole and ignoring case
+    pattern: Regex,
+}
+
+impl Lexicon {
+    /// `None` when there's nothing to replace.
+    pub fn new(words: &BTreeMap<String, String>) -> Option<Lexicon> {
+        let mut keys: Vec<&String> = words.keys().filter(|k| !k.trim().is_empty()).collect();
+        if keys.is_empty() {
+            return None;
+        }
+        // Longest first, so "binaural beats" wins over "binaural".
+        keys.sort_by_key(|k| std::cmp::Reverse(k.chars().count()));
+        let alternatives: Vec<String> = keys.iter().map(|k| regex::escape(k.trim())).collect();
+        let pattern = Regex::new(&format!(r"(?i)\b(?:{})\b", alternatives.join("|"))).unwrap();
+        let entries = words
+            .iter()
+            .map(|(word, replacement)| (word.trim().to_lowercase(), replacement.clone()))
+            .collect();
+        Some(Lexicon { entries, pattern })
+    }
+
+    /// A YAML mapping of words to their replacements.
+    pub fn load_file(path: &Path) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error>> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("failed to read lexicon file {:?}: {}", path, err))?;
+        let words = serde_yaml::from_str(&text)
+            .map_err(|err| format!("invalid lexicon file {:?}: {}", path, err))?;
+        Ok(words)
+    }
+
+    /// The text with every listed word replaced. Text without any of them is borrowed as it is.
+    pub fn apply<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        self.pattern.replace_all(text, |caps: &Captures| {
+            self.entries
+                .get(&caps[0].to_lowercase())
+                .cloned()
+                .unwrap_or_else(|| caps[0].to_string())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lexicon(pairs: &[(&str, &str)]) -> Lexicon {
+        let words = pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Lexicon::new(&words).unwrap()
+    }
+
+    #[test]
+    fn test_apply() {
+        let lex = lexicon(&[
+            ("theta", "thayta"),
+            ("Hz", "hertz"),
+            ("binaural beats", "bye-nawral beats"),
+            ("binaural", "bye-nawral"),
+        ]);
+        assert_eq!(
+            lex.apply("Theta waves at 4 Hz. Binaural beats are binaural."),
+            "thayta waves at 4 hertz. bye-nawral beats are bye-nawral."
+        );
+        // Only whole words.
+        assert_eq!(lex.apply("thetas and hzz"), "thetas and hzz");
+        assert!(matches!(lex.apply("nothing here"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_empty() {
+        assert!(Lexicon::new(&BTreeMap::new()).is_none());
+    }
+}
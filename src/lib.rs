@@ -1,12 +1,19 @@
 pub mod analysis;
+pub mod cache;
 pub mod config;
+pub mod duck;
 pub mod fileutils;
+pub mod lexicon;
 pub mod logger;
+pub mod markup;
 pub mod mixin;
 pub mod noise;
 pub mod render;
 pub mod sink;
+pub mod speechfx;
+pub mod subtitles;
 pub mod sysconfig;
 pub mod timeutils;
 pub mod tts;
 pub mod utils;
+pub mod verify;
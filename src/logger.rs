@@ -3,11 +3,20 @@ use log::LevelFilter;
 use std::io::Write;
 
 pub fn init(verbose: bool) {
+    init_to(verbose, Target::Stdout);
+}
+
+/// Log to stderr, eg: when the audio itself is going to stdout.
+pub fn init_stderr(verbose: bool) {
+    init_to(verbose, Target::Stderr);
+}
+
+fn init_to(verbose: bool, target: Target) {
     let mut builder = Builder::from_default_env();
 
     builder
         .format_timestamp_secs()
-        .target(Target::Stdout)
+        .target(target)
         // Just the default filter if RUST_LOG isn’t set
         .filter_level(if verbose {
             LevelFilter::Debug
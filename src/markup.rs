@@ -0,0 +1,348 @@
+//! A small markup for TTS text, so one line can hold exact pauses and changes of speed:
+//!
+//! ```text
+//! Breathe in... [pause 4s] ...and out.
+//!
+//! [speed 0.8]Slowly now.[/speed] Let it go.
+//! ```
+//!
+//! Blank lines between paragraphs become a pause of `PARAGRAPH_PAUSE_SECS`. Each piece of speech
+//! is generated separately and the clips are joined with the exact silences in between.
+use crate::timeutils::DurationSeconds;
+use hound::{WavReader, WavWriter};
+use log::warn;
+use regex::Regex;
+use std::fmt;
+use std::path::Path;
+use std::sync::LazyLock;
+
+/// The pause for a blank line between paragraphs.
+pub const PARAGRAPH_PAUSE_SECS: f32 = 1.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Span {
+    /// Text to speak, at `speed` times the voice's normal speed.
+    Speech { text: String, speed: f32 },
+    /// Seconds of silence.
+    Pause(f32),
+}
+
+#[derive(Debug)]
+pub struct MarkupError(pub String);
+
+impl fmt::Display for MarkupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid TTS markup: {}", self.0)
+    }
+}
+
+impl std::error::Error for MarkupError {}
+
+/// Only these are markup. Anything else in brackets, eg: `[inhale]`, is left in the text, since
+/// lines written before there was markup could have it and were spoken as they are.
+static TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\[\s*(pause|speed|/speed)\b\s*([^\]]*)\]").unwrap());
+static PARAGRAPH: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\n[ \t]*\n\s*").unwrap());
+
+/// Split text into speech and pauses. Text without any markup comes back as one `Speech`.
+pub fn parse(text: &str) -> Result<Vec<Span>, MarkupError> {
+    let mut spans: Vec<Span> = Vec::new();
+    let mut speed = 1.0_f32;
+    let mut last = 0;
+    for caps in TAG.captures_iter(text) {
+        let whole = caps.get(0).unwrap();
+        push_text(&mut spans, &text[last..whole.start()], speed);
+        last = whole.end();
+
+        let arg = caps[2].trim();
+        match &caps[1] {
+            "pause" => {
+                let secs = arg
+                    .parse::<DurationSeconds>()
+                    .map_err(|err| MarkupError(format!("{:?}: {}", whole.as_str(), err)))?;
+                push_pause(&mut spans, secs.0);
+            }
+            "speed" => {
+                speed = arg
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|s| *s > 0.0)
+                    .ok_or_else(|| {
+                        MarkupError(format!(
+                            "{:?}: speed must be a number above 0",
+                            whole.as_str()
+                        ))
+                    })?;
+            }
+            _ => speed = 1.0,
+        }
+    }
+    push_text(&mut spans, &text[last..], speed);
+    Ok(spans)
+}
+
+fn push_text(spans: &mut Vec<Span>, text: &str, speed: f32) {
+    for (i, para) in PARAGRAPH.split(text).enumerate() {
+        if i > 0 {
+            push_pause(spans, PARAGRAPH_PAUSE_SECS);
+        }
+        let para = para.trim();
+        if !para.is_empty() {
+            spans.push(Span::Speech {
+                text: para.to_string(),
+                speed,
+            });
+        }
+    }
+}
+
+/// Pauses next to each other add up.
+fn push_pause(spans: &mut Vec<Span>, secs: f32) {
+    if let Some(Span::Pause(prev)) = spans.last_mut() {
+        *prev += secs;
+    } else {
+        spans.push(Span::Pause(secs));
+    }
+}
+
+/// Just the words of marked up text on one line, for showing to people.
+pub fn plain_text(text: &str) -> Result<String, MarkupError> {
+    let spans = parse(text)?;
+    let mut words: Vec<&str> = Vec::new();
+    for span in spans.iter() {
+        if let Span::Speech { text, .. } = span {
+            words.extend(text.split_whitespace());
+        }
+    }
+    Ok(words.join(" "))
+}
+
+/// Whether the text needs splitting up, rather than going to the TTS engine as it is.
+pub fn has_markup(spans: &[Span]) -> bool {
+    !matches!(spans, [Span::Speech { speed, .. }] if *speed == 1.0)
+}
+
+/// A piece of the assembled clip.
+#[derive(Debug)]
+pub enum Piece<'a> {
+    Clip(&'a Path),
+    Silence(f32),
+}
+
+/// Join generated clips and silences into one WAV at `out`, in the format of the first clip. The
+/// clips are expected to come from the same voice, so they share a format.
+pub fn assemble(pieces: &[Piece], out: &Path) -> Result<(), hound::Error> {
+    let spec = pieces
+        .iter()
+        .find_map(|p| match p {
+            Piece::Clip(path) => Some(path),
+            Piece::Silence(_) => None,
+        })
+        .map(|path| WavReader::open(path).map(|r| r.spec()))
+        .transpose()?
+        .unwrap_or(hound::WavSpec {
+            channels: 1,
+            sample_rate: 22_050,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        });
+
+    // Appended rather than swapping the extension, so keys like intro.v2 and intro.v3 don't share
+    // a partial file.
+    let mut partial = out.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = Path::new(&partial);
+    let write = || -> Result<(), hound::Error> {
+        let mut writer = WavWriter::create(partial, spec)?;
+        for piece in pieces {
+            match piece {
+                Piece::Clip(path) => {
+                    let mut reader = WavReader::open(path)?;
+                    if reader.spec() != spec {
+                        return Err(hound::Error::FormatError(
+                            "clips to assemble have different formats",
+                        ));
+                    }
+                    match spec.sample_format {
+                        hound::SampleFormat::Float => {
+                            for s in reader.samples::<f32>() {
+                                writer.write_sample(s?)?;
+                            }
+                        }
+                        hound::SampleFormat::Int => {
+                            for s in reader.samples::<i32>() {
+                                writer.write_sample(s?)?;
+                            }
+                        }
+                    }
+                }
+                Piece::Silence(secs) => {
+                    let frames = crate::utils::secs_to_samples(*secs, spec.sample_rate);
+                    for _ in 0..frames * spec.channels as usize {
+                        match spec.sample_format {
+                            hound::SampleFormat::Float => writer.write_sample(0.0_f32)?,
+                            hound::SampleFormat::Int => writer.write_sample(0_i32)?,
+                        }
+                    }
+                }
+            }
+        }
+        writer.finalize()?;
+        std::fs::rename(partial, out)?;
+        Ok(())
+    };
+    if let Err(err) = write() {
+        if let Err(rm_err) = std::fs::remove_file(partial)
+            && rm_err.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!("failed to remove {:?}: {}", partial, rm_err);
+        }
+        return Err(err);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn speech(text: &str, speed: f32) -> Span {
+        Span::Speech {
+            text: text.to_string(),
+            speed,
+        }
+    }
+
+    #[test]
+    fn test_plain_text() {
+        let spans = parse("  Breathe in.\nHold it.  ").unwrap();
+        assert_eq!(spans, vec![speech("Breathe in.\nHold it.", 1.0)]);
+        assert!(!has_markup(&spans));
+    }
+
+    #[test]
+    fn test_pauses_and_paragraphs() {
+        let spans = parse("Breathe in... [pause 4s] ...and out.\n\nRelax.[pause 0.5]").unwrap();
+        assert_eq!(
+            spans,
+            vec![
+                speech("Breathe in...", 1.0),
+                Span::Pause(4.0),
+                speech("...and out.", 1.0),
+                Span::Pause(PARAGRAPH_PAUSE_SECS),
+                speech("Relax.", 1.0),
+                Span::Pause(0.5),
+            ]
+        );
+        assert!(has_markup(&spans));
+    }
+
+    #[test]
+    fn test_adjacent_pauses_add_up() {
+        let spans = parse("One. [pause 1s]\n\n[pause 2s] Two.").unwrap();
+        assert_eq!(
+            spans,
+            vec![
+                speech("One.", 1.0),
+                Span::Pause(3.0 + PARAGRAPH_PAUSE_SECS),
+                speech("Two.", 1.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_speed() {
+        let spans = parse("[speed 0.8]Slowly now.[/speed] Let it go.").unwrap();
+        assert_eq!(
+            spans,
+            vec![speech("Slowly now.", 0.8), speech("Let it go.", 1.0)]
+        );
+        assert!(has_markup(&parse("[speed 0.8]Slowly.").unwrap()));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(parse("[pause soon]").is_err());
+        assert!(parse("[speed 0]").is_err());
+    }
+
+    #[test]
+    fn test_unknown_tags_are_text() {
+        let spans = parse("[inhale] Breathe in. [sighs] [speedy 2]").unwrap();
+        assert_eq!(
+            spans,
+            vec![speech("[inhale] Breathe in. [sighs] [speedy 2]", 1.0)]
+        );
+        assert!(!has_markup(&spans));
+    }
+
+    #[test]
+    fn test_plain_text_strips_markup() {
+        assert_eq!(
+            plain_text("Breathe in...\n[pause 4s] ...and out.\n\n[speed 0.8]Slowly[/speed]")
+                .unwrap(),
+            "Breathe in... ...and out. Slowly"
+        );
+    }
+
+    #[test]
+    fn test_assemble() {
+        let dir = tempfile::tempdir().unwrap();
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let clip = dir.path().join("clip");
+        let mut writer = WavWriter::create(&clip, spec).unwrap();
+        for _ in 0..10 {
+            writer.write_sample(1000_i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let out = dir.path().join("out");
+        assemble(
+            &[Piece::Clip(&clip), Piece::Silence(0.5), Piece::Clip(&clip)],
+            &out,
+        )
+        .unwrap();
+        let samples: Vec<i16> = WavReader::open(&out)
+            .unwrap()
+            .samples::<i16>()
+            .map(|s| s.unwrap())
+            .collect();
+        assert_eq!(samples.len(), 70);
+        assert_eq!(samples[9], 1000);
+        assert_eq!(samples[10], 0);
+        assert_eq!(samples[59], 0);
+        assert_eq!(samples[60], 1000);
+    }
+
+    #[test]
+    fn test_failed_assemble_leaves_no_partial() {
+        let dir = tempfile::tempdir().unwrap();
+        let clip = |name: &str, sample_rate: u32| {
+            let path = dir.path().join(name);
+            let spec = hound::WavSpec {
+                channels: 1,
+                sample_rate,
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            };
+            let mut writer = WavWriter::create(&path, spec).unwrap();
+            writer.write_sample(1000_i16).unwrap();
+            writer.finalize().unwrap();
+            path
+        };
+        let (a, b) = (clip("a", 100), clip("b", 200));
+        let out = dir.path().join("_tts_intro.v2");
+        assert!(assemble(&[Piece::Clip(&a), Piece::Clip(&b)], &out).is_err());
+        let mut left: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        left.sort();
+        assert_eq!(left, vec!["a", "b"]);
+    }
+}
@@ -1,27 +1,104 @@
-use crate::config::{AudioSpec, TTSSpec};
+use crate::cache::PCM_PREFIX;
+use crate::config::{AudioSpec, Overflow, TTSSpec};
+use crate::fileutils::sha256_file;
 use crate::utils;
-use log::debug;
+use log::{debug, info, warn};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
 use std::path::{Path, PathBuf};
 
-use hound;
+use hound::{self, SampleFormat, WavIntoSamples, WavSpec, WavWriter};
 
 #[derive(Debug, Clone)]
 pub struct Mixin {
     pub gain: f32,
     pub path: PathBuf,
     pub offset: f32,
+    pub overflow: Overflow,
+    /// Where this came from in the config, for error messages.
+    pub segment: usize,
+    pub index: usize,
+    /// Where to keep decoded and resampled copies of the source, if anywhere.
+    pub cache_dir: Option<PathBuf>,
+    /// The decoded and resampled copy to stream from instead of `path`.
+    pub decoded: Option<PathBuf>,
+    /// What's said, for TTS mixins, so it can be written out as subtitles.
+    pub text: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum MixinErrorKind {
+    /// The file is missing or its header couldn't be parsed.
+    Open(hound::Error),
+    /// The file is truncated or a sample couldn't be decoded.
+    Decode(hound::Error),
+    /// It runs past the end of its segment with `overflow: error`.
+    Overflow { secs: f32 },
+    /// The decoded copy couldn't be written to the cache.
+    Cache(std::io::Error),
+}
+
+/// A mixin that couldn't be rendered, naming where it is in the config.
+#[derive(Debug)]
+pub struct MixinError {
+    pub path: PathBuf,
+    pub segment: usize,
+    pub index: usize,
+    pub kind: MixinErrorKind,
+}
+
+impl fmt::Display for MixinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "segment {} mixin {} ({}): ",
+            self.segment,
+            self.index,
+            self.path.display()
+        )?;
+        match &self.kind {
+            MixinErrorKind::Open(err) => write!(f, "failed to open wav: {}", err),
+            MixinErrorKind::Decode(err) => write!(f, "failed to decode wav: {}", err),
+            MixinErrorKind::Overflow { secs } => write!(
+                f,
+                "runs {:.2}s past the end of its segment (overflow: error)",
+                secs
+            ),
+            MixinErrorKind::Cache(err) => write!(f, "failed to cache decoded audio: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for MixinError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            MixinErrorKind::Open(err) | MixinErrorKind::Decode(err) => Some(err),
+            MixinErrorKind::Cache(err) => Some(err),
+            MixinErrorKind::Overflow { .. } => None,
+        }
+    }
 }
 
 impl From<TTSSpec> for Mixin {
     fn from(tts: TTSSpec) -> Self {
         debug!(
             "Converting TTSSpec to Mixin at {:?} with gain {}",
-            tts._out_path, tts.gain
+            tts.clip_path(),
+            tts.gain
         );
         Mixin {
             gain: tts.gain,
-            path: tts._out_path,
+            path: tts.clip_path().to_path_buf(),
             offset: tts.offset.0,
+            overflow: tts.overflow.unwrap_or_default(),
+            segment: 0,
+            index: 0,
+            cache_dir: None,
+            decoded: None,
+            text: Some(tts.text),
         }
     }
 }
@@ -36,6 +113,12 @@ impl From<AudioSpec> for Mixin {
             gain: audio.gain,
             path: audio._path,
             offset: audio.offset.0,
+            overflow: audio.overflow.unwrap_or_default(),
+            segment: 0,
+            index: 0,
+            cache_dir: None,
+            decoded: None,
+            text: None,
         }
     }
 }
@@ -44,23 +127,376 @@ impl Mixin {
     pub fn sample_offset(&self, sample_rate: u32) -> usize {
         utils::secs_to_samples(self.offset, sample_rate)
     }
-    pub fn render(&self, dest: &mut [f32], out_sr: u32) -> std::io::Result<()> {
-        debug!(
-            "Loading in mixin of {:?} at sample rate {}",
-            self.path, out_sr
+
+    /// How long the clip is in seconds, from its WAV header.
+    pub fn duration(&self) -> Result<f32, hound::Error> {
+        let reader = hound::WavReader::open(&self.path)?;
+        Ok(reader.duration() as f32 / reader.spec().sample_rate as f32)
+    }
+
+    fn error(&self, kind: MixinErrorKind) -> MixinError {
+        MixinError {
+            path: self.path.clone(),
+            segment: self.segment,
+            index: self.index,
+            kind,
+        }
+    }
+
+    /// Check the header can be read, so broken files are caught before rendering starts. With a
+    /// `cache_dir`, a source that isn't already mono at `out_sr` is decoded and resampled once into
+    /// the cache, and the path of that copy is returned.
+    pub fn prepare(&self, out_sr: u32) -> Result<Option<PathBuf>, MixinError> {
+        let spec = hound::WavReader::open(&self.path)
+            .map_err(|err| self.error(MixinErrorKind::Open(err)))?
+            .spec();
+        match &self.cache_dir {
+            Some(cache_dir) if spec.sample_rate != out_sr || spec.channels != 1 => {
+                self.decode_to_cache(cache_dir, out_sr).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Keyed by the source contents and the output sample rate, stored as a mono f32 WAV.
+    fn decode_to_cache(&self, cache_dir: &Path, out_sr: u32) -> Result<PathBuf, MixinError> {
+        let cache_err = |err: std::io::Error| self.error(MixinErrorKind::Cache(err));
+        let name = decoded_name(&self.path, out_sr).map_err(cache_err)?;
+        let cached = cache_dir.join(&name);
+        if cached.exists() {
+            debug!("using decoded {:?} for {:?}", cached, self.path);
+            return Ok(cached);
+        }
+
+        info!(
+            "decoding and resampling {:?} to {} into {:?}",
+            self.path, out_sr, cached
         );
-        let (samples, in_sr) = load_wav_to_f32(&self.path)?;
-        let resampled = resample_linear(&samples, in_sr, out_sr);
-        let offset_samples = self.sample_offset(out_sr);
-
-        for (i, &s) in resampled.iter().enumerate() {
-            let idx = offset_samples + i;
-            if idx < dest.len() {
-                dest[idx] += s * self.gain;
-            } else {
+        let mut source = self.clone();
+        source.decoded = None;
+        let mut reader = MixinReader::open(&source, out_sr).map_err(|kind| self.error(kind))?;
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: out_sr,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        // Written under another name first, so an interrupted run never leaves a partial copy, and
+        // removed if anything goes wrong, so a broken source doesn't leave one behind either.
+        let partial = cache_dir.join(format!("{}.partial", name));
+        let hound_err = |err: hound::Error| cache_err(std::io::Error::other(err));
+        let mut write = || -> Result<(), MixinError> {
+            let mut writer = WavWriter::create(&partial, spec).map_err(hound_err)?;
+            for _ in 0..reader.len {
+                let sample = reader.next_sample().map_err(|kind| self.error(kind))?;
+                writer.write_sample(sample).map_err(hound_err)?;
+            }
+            writer.finalize().map_err(hound_err)?;
+            std::fs::rename(&partial, &cached).map_err(cache_err)
+        };
+        if let Err(err) = write() {
+            if let Err(rm_err) = std::fs::remove_file(&partial)
+                && rm_err.kind() != std::io::ErrorKind::NotFound
+            {
+                warn!("failed to remove {:?}: {}", partial, rm_err);
+            }
+            return Err(err);
+        }
+        Ok(cached)
+    }
+
+    /// Open a streaming reader for this mixin, given where its segment starts and ends in the
+    /// session (in output samples). The overflow policy is applied here, since the header tells us
+    /// how long the mixin is before decoding any of it.
+    pub fn open(
+        &self,
+        out_sr: u32,
+        segment_start: usize,
+        segment_end: usize,
+        session_end: usize,
+    ) -> Result<MixinReader, MixinError> {
+        debug!("Opening mixin of {:?} at sample rate {}", self.path, out_sr);
+        let mut reader = MixinReader::open(self, out_sr).map_err(|kind| self.error(kind))?;
+        let start = segment_start + self.sample_offset(out_sr);
+        let mut end = start + reader.len;
+
+        if end > segment_end {
+            let over_secs = (end - segment_end) as f32 / out_sr as f32;
+            match self.overflow {
+                Overflow::Continue => debug!(
+                    "mixin {:?} continues {:.2}s past the end of its segment",
+                    self.path, over_secs
+                ),
+                Overflow::Truncate => {
+                    warn!(
+                        "mixin {:?} was cut off {:.2}s before its end by the end of its segment",
+                        self.path, over_secs
+                    );
+                    end = segment_end;
+                }
+                Overflow::Error => {
+                    return Err(self.error(MixinErrorKind::Overflow { secs: over_secs }));
+                }
+            }
+        }
+        if end > session_end {
+            warn!(
+                "mixin {:?} ran {:.2}s past the end of the last segment and was cut off",
+                self.path,
+                (end - session_end) as f32 / out_sr as f32
+            );
+            end = session_end;
+        }
+
+        reader.start = start;
+        reader.end = end;
+        Ok(reader)
+    }
+}
+
+/// The name of the decoded copy of a source in the cache, keyed by its contents and the output
+/// sample rate, eg: "_pcm_<sha256>_48000"
+pub fn decoded_name(source: &Path, out_sr: u32) -> std::io::Result<String> {
+    Ok(format!("{}{}_{}", PCM_PREFIX, sha256_file(source)?, out_sr))
+}
+
+/// Samples as they come out of the WAV file, before being scaled to f32.
+enum SampleSource {
+    Int(WavIntoSamples<BufReader<File>, i32>, f32),
+    Float(WavIntoSamples<BufReader<File>, f32>),
+}
+
+/// Decodes a WAV file frame by frame, downmixes it to mono and resamples it to the output sample
+/// rate as the render advances, so memory stays bounded no matter how long the source is.
+pub struct MixinReader {
+    mixin: Mixin,
+    source: SampleSource,
+    channels: usize,
+    /// How far we step through the source for each output sample (in_sr / out_sr).
+    step: f64,
+    /// Position between `s0` and `s1`, in [0.0, 1.0).
+    pos: f64,
+    s0: f32,
+    s1: f32,
+    /// Length in output samples.
+    len: usize,
+    /// Absolute output sample where this starts and stops playing.
+    start: usize,
+    end: usize,
+}
+
+impl MixinReader {
+    fn open(mixin: &Mixin, out_sr: u32) -> Result<Self, MixinErrorKind> {
+        let path = mixin.decoded.as_ref().unwrap_or(&mixin.path);
+        let reader = hound::WavReader::open(path).map_err(MixinErrorKind::Open)?;
+        let spec = reader.spec();
+        let in_sr = spec.sample_rate;
+        let frames = reader.duration() as usize;
+        let source = match spec.sample_format {
+            SampleFormat::Float => SampleSource::Float(reader.into_samples::<f32>()),
+            SampleFormat::Int => {
+                let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+                SampleSource::Int(reader.into_samples::<i32>(), scale)
+            }
+        };
+
+        let mut mixin_reader = MixinReader {
+            mixin: mixin.clone(),
+            source,
+            channels: spec.channels.max(1) as usize,
+            step: in_sr as f64 / out_sr as f64,
+            pos: 0.0,
+            s0: 0.0,
+            s1: 0.0,
+            len: (frames as f64 * out_sr as f64 / in_sr as f64) as usize,
+            start: 0,
+            end: 0,
+        };
+        mixin_reader.s0 = mixin_reader.next_frame()?.unwrap_or(0.0);
+        mixin_reader.s1 = mixin_reader.next_frame()?.unwrap_or(mixin_reader.s0);
+        Ok(mixin_reader)
+    }
+
+    /// The next frame of the source averaged to mono, or None at the end of the file.
+    fn next_frame(&mut self) -> Result<Option<f32>, MixinErrorKind> {
+        let mut sum = 0.0;
+        for ch in 0..self.channels {
+            let sample = match &mut self.source {
+                SampleSource::Int(samples, scale) => {
+                    samples.next().map(|s| s.map(|s| s as f32 * *scale))
+                }
+                SampleSource::Float(samples) => samples.next(),
+            };
+            match sample {
+                Some(Ok(s)) => sum += s,
+                Some(Err(err)) => return Err(MixinErrorKind::Decode(err)),
+                None if ch == 0 => return Ok(None),
+                None => break,
+            }
+        }
+        Ok(Some(sum / self.channels as f32))
+    }
+
+    /// The next sample at the output sample rate, linearly interpolated like `resample_linear`.
+    fn next_sample(&mut self) -> Result<f32, MixinErrorKind> {
+        let out = self.s0 + (self.s1 - self.s0) * self.pos as f32;
+        self.pos += self.step;
+        while self.pos >= 1.0 {
+            self.s0 = self.s1;
+            // Past the end of the source, hold the last sample.
+            self.s1 = self.next_frame()?.unwrap_or(self.s0);
+            self.pos -= 1.0;
+        }
+        Ok(out)
+    }
+
+    /// Add this mixin into `buf`, which holds the session samples starting at `buf_start`.
+    pub fn read_into(&mut self, buf_start: usize, buf: &mut [f32]) -> Result<(), MixinError> {
+        let from = self.start.max(buf_start);
+        let to = self.end.min(buf_start + buf.len());
+        for idx in from..to {
+            let sample = self.next_sample().map_err(|kind| self.mixin.error(kind))?;
+            buf[idx - buf_start] += sample * self.mixin.gain;
+        }
+        Ok(())
+    }
+
+    /// Whether everything has been played by the time the session reaches `pos`.
+    pub fn is_done(&self, pos: usize) -> bool {
+        pos >= self.end
+    }
+}
+
+/// A mixin waiting for the render to reach its segment.
+#[derive(Debug)]
+struct Scheduled {
+    /// The session sample the mixin starts on, which is all it's ordered by.
+    start: usize,
+    mixin: Mixin,
+    segment_start: usize,
+    segment_end: usize,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.start == other.start
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.start.cmp(&other.start)
+    }
+}
+
+/// With `skip_broken`, files that fail to open or decode are only warned about. Overflow errors
+/// are always returned, since those are the config asking to fail.
+fn skip_or_fail(skip_broken: bool, err: MixinError) -> Result<(), MixinError> {
+    match err.kind {
+        MixinErrorKind::Open(_) | MixinErrorKind::Decode(_) if skip_broken => {
+            warn!("skipping broken mixin: {}", err);
+            Ok(())
+        }
+        _ => Err(err),
+    }
+}
+
+/// Schedules the mixins of every segment over the whole session, and streams the ones currently
+/// playing into the mix buffer block by block.
+pub struct MixBus {
+    sample_rate: u32,
+    session_end: usize,
+    /// Warn about and leave out mixins that fail to decode, rather than failing the render.
+    skip_broken: bool,
+    /// The next mixin to start is on top.
+    pending: BinaryHeap<Reverse<Scheduled>>,
+    active: Vec<MixinReader>,
+    /// Sources already prepared, so a clip used many times is only hashed and decoded once.
+    prepared: HashMap<PathBuf, Option<PathBuf>>,
+}
+
+impl MixBus {
+    pub fn new(sample_rate: u32, session_end: usize, skip_broken: bool) -> Self {
+        Self {
+            sample_rate,
+            session_end,
+            skip_broken,
+            pending: BinaryHeap::new(),
+            active: Vec::new(),
+            prepared: HashMap::new(),
+        }
+    }
+
+    /// Schedule a mixin to play over its segment, checking it can be opened.
+    pub fn schedule(
+        &mut self,
+        mut mixin: Mixin,
+        segment_start: usize,
+        segment_len: usize,
+    ) -> Result<(), MixinError> {
+        let prepared = match self.prepared.get(&mixin.path) {
+            Some(decoded) => decoded.clone(),
+            None => match mixin.prepare(self.sample_rate) {
+                Ok(decoded) => {
+                    self.prepared.insert(mixin.path.clone(), decoded.clone());
+                    decoded
+                }
+                Err(err) => return self.skip_or_fail(err),
+            },
+        };
+        mixin.decoded = prepared;
+        self.pending.push(Reverse(Scheduled {
+            start: segment_start + mixin.sample_offset(self.sample_rate),
+            mixin,
+            segment_start,
+            segment_end: segment_start + segment_len,
+        }));
+        Ok(())
+    }
+
+    fn skip_or_fail(&self, err: MixinError) -> Result<(), MixinError> {
+        skip_or_fail(self.skip_broken, err)
+    }
+
+    /// Overwrite `buf` with the sum of every mixin playing from `buf_start` onward.
+    pub fn fill(&mut self, buf_start: usize, buf: &mut [f32]) -> Result<(), MixinError> {
+        buf.fill(0.0);
+        let buf_end = buf_start + buf.len();
+        while let Some(Reverse(next)) = self.pending.peek() {
+            if next.start >= buf_end {
                 break;
             }
+            let Reverse(next) = self.pending.pop().unwrap();
+            match next.mixin.open(
+                self.sample_rate,
+                next.segment_start,
+                next.segment_end,
+                self.session_end,
+            ) {
+                Ok(reader) => self.active.push(reader),
+                Err(err) => self.skip_or_fail(err)?,
+            }
+        }
+        let mut broken: Vec<usize> = Vec::new();
+        for (i, reader) in self.active.iter_mut().enumerate() {
+            if let Err(err) = reader.read_into(buf_start, buf) {
+                // Whatever was decoded before the error stays in the mix.
+                skip_or_fail(self.skip_broken, err)?;
+                broken.push(i);
+            }
+        }
+        for i in broken.into_iter().rev() {
+            self.active.remove(i);
         }
+        self.active.retain(|r| !r.is_done(buf_end));
         Ok(())
     }
 }
@@ -79,8 +515,14 @@ pub fn load_wav_to_f32(path: &Path) -> std::io::Result<(Vec<f32>, u32)> {
 
     let samples: Vec<f32> = reader
         .samples::<i16>() // or f32 if file is float
-        .map(|s| s.unwrap() as f32 / i16::MAX as f32)
-        .collect();
+        .map(|s| s.map(|s| s as f32 / i16::MAX as f32))
+        .collect::<Result<_, _>>()
+        .map_err(|err| {
+            std::io::Error::other(format!(
+                "hound: {:?} - failed to decode sample from {:?}",
+                err, path
+            ))
+        })?;
 
     Ok((samples, sr))
 }
@@ -112,3 +554,221 @@ pub fn resample_linear(input: &[f32], in_sr: u32, out_sr: u32) -> Vec<f32> {
 
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hound::{WavSpec, WavWriter};
+    use tempfile::tempdir;
+
+    fn write_wav(path: &Path, sample_rate: u32, samples: &[i16]) {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(path, spec).unwrap();
+        for &s in samples {
+            writer.write_sample(s).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    fn mixin(path: &Path, offset: f32, overflow: Overflow) -> Mixin {
+        Mixin {
+            gain: 1.0,
+            path: path.to_path_buf(),
+            offset,
+            overflow,
+            segment: 2,
+            index: 1,
+            cache_dir: None,
+            decoded: None,
+            text: None,
+        }
+    }
+
+    #[test]
+    fn test_streaming_matches_resample_linear() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("clip.wav");
+        let samples: Vec<i16> = (0..1000).map(|i| ((i * 37) % 2000 - 1000) as i16).collect();
+        write_wav(&path, 22_050, &samples);
+
+        let (loaded, in_sr) = load_wav_to_f32(&path).unwrap();
+        let expected = resample_linear(&loaded, in_sr, 48_000);
+
+        let mut bus = MixBus::new(48_000, 10_000, false);
+        bus.schedule(mixin(&path, 0.0, Overflow::Continue), 0, 10_000)
+            .unwrap();
+        let mut out = Vec::new();
+        let mut block = vec![0.0; 300];
+        for n in (0..3000).step_by(block.len()) {
+            bus.fill(n, &mut block).unwrap();
+            out.extend_from_slice(&block);
+        }
+        assert_eq!(expected.len(), 2176);
+        for (i, (a, b)) in expected.iter().zip(&out).enumerate() {
+            // The scale differs slightly, i16::MAX vs 2^15.
+            assert!((a - b).abs() < 1e-3, "sample {} was {} vs {}", i, b, a);
+        }
+        assert!(out[expected.len()..].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn test_overflow_policies() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("clip.wav");
+        write_wav(&path, 1000, &[16_384; 100]);
+
+        let sum = |overflow: Overflow| {
+            let mut bus = MixBus::new(1000, 1000, false);
+            bus.schedule(mixin(&path, 0.05, overflow), 0, 100).unwrap();
+            let mut block = vec![0.0; 1000];
+            bus.fill(0, &mut block).map(|_| block.iter().sum::<f32>())
+        };
+        assert!((sum(Overflow::Continue).unwrap() - 50.0).abs() < 1e-3);
+        assert!((sum(Overflow::Truncate).unwrap() - 25.0).abs() < 1e-3);
+        assert!(sum(Overflow::Error).is_err());
+    }
+
+    #[test]
+    fn test_later_segment_starts_at_its_offset() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("clip.wav");
+        write_wav(&path, 1000, &[16_384; 10]);
+
+        let mut bus = MixBus::new(1000, 1000, false);
+        bus.schedule(mixin(&path, 0.01, Overflow::Continue), 500, 500)
+            .unwrap();
+        let mut block = vec![0.0; 100];
+        bus.fill(0, &mut block).unwrap();
+        assert!(block.iter().all(|&s| s == 0.0));
+        bus.fill(500, &mut block).unwrap();
+        assert_eq!(block[9], 0.0);
+        assert_eq!(block[10], 0.5);
+        assert_eq!(block[19], 0.5);
+        assert_eq!(block[20], 0.0);
+    }
+
+    #[test]
+    fn test_mixins_start_in_order_however_scheduled() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("clip.wav");
+        write_wav(&path, 1000, &[16_384; 10]);
+
+        let mut bus = MixBus::new(1000, 1000, false);
+        // A late offset in an early segment starts after an early offset in a later one.
+        bus.schedule(mixin(&path, 0.3, Overflow::Continue), 0, 500)
+            .unwrap();
+        bus.schedule(mixin(&path, 0.0, Overflow::Continue), 500, 500)
+            .unwrap();
+        bus.schedule(mixin(&path, 0.1, Overflow::Continue), 0, 500)
+            .unwrap();
+        let mut out = Vec::new();
+        let mut block = vec![0.0; 100];
+        for n in (0..1000).step_by(block.len()) {
+            bus.fill(n, &mut block).unwrap();
+            out.extend_from_slice(&block);
+        }
+        let starts: Vec<usize> = (1..out.len())
+            .filter(|&i| out[i] != 0.0 && out[i - 1] == 0.0)
+            .collect();
+        assert_eq!(starts, vec![100, 300, 500]);
+    }
+
+    #[test]
+    fn test_truncated_wav_errors_with_location() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("clip.wav");
+        write_wav(&path, 1000, &[16_384; 100]);
+        let bytes = std::fs::read(&path).unwrap();
+        // Cut it off mid-sample.
+        std::fs::write(&path, &bytes[..bytes.len() - 101]).unwrap();
+
+        let mut bus = MixBus::new(1000, 1000, false);
+        bus.schedule(mixin(&path, 0.0, Overflow::Continue), 0, 1000)
+            .unwrap();
+        let mut block = vec![0.0; 1000];
+        let err = bus.fill(0, &mut block).unwrap_err();
+        assert!(matches!(err.kind, MixinErrorKind::Decode(_)));
+        assert!(
+            err.to_string().starts_with("segment 2 mixin 1 ("),
+            "{}",
+            err
+        );
+
+        let mut bus = MixBus::new(1000, 1000, true);
+        bus.schedule(mixin(&path, 0.0, Overflow::Continue), 0, 1000)
+            .unwrap();
+        bus.fill(0, &mut block).unwrap();
+        assert!((block[0] - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_missing_wav_can_be_skipped() {
+        let path = PathBuf::from("/nonexistent/clip.wav");
+        let mut bus = MixBus::new(1000, 1000, false);
+        let err = bus
+            .schedule(mixin(&path, 0.0, Overflow::Continue), 0, 1000)
+            .unwrap_err();
+        assert!(matches!(err.kind, MixinErrorKind::Open(_)));
+
+        let mut bus = MixBus::new(1000, 1000, true);
+        bus.schedule(mixin(&path, 0.0, Overflow::Continue), 0, 1000)
+            .unwrap();
+        let mut block = vec![0.0; 10];
+        bus.fill(0, &mut block).unwrap();
+        assert!(block.iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn test_truncated_source_leaves_no_partial_cache() {
+        let dir = tempdir().unwrap();
+        let cache_dir = dir.path().join("cache");
+        std::fs::create_dir(&cache_dir).unwrap();
+        let path = dir.path().join("clip.wav");
+        write_wav(&path, 22_050, &[16_384; 1000]);
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 501]).unwrap();
+
+        let mut m = mixin(&path, 0.0, Overflow::Continue);
+        m.cache_dir = Some(cache_dir.clone());
+        let mut bus = MixBus::new(48_000, 3000, false);
+        let err = bus.schedule(m, 0, 3000).unwrap_err();
+        assert!(matches!(err.kind, MixinErrorKind::Decode(_)), "{}", err);
+        assert_eq!(std::fs::read_dir(&cache_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_decoded_cache_matches_and_is_reused() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("clip.wav");
+        let samples: Vec<i16> = (0..1000).map(|i| ((i * 37) % 2000 - 1000) as i16).collect();
+        write_wav(&path, 22_050, &samples);
+
+        let render = |cache_dir: Option<PathBuf>| {
+            let mut m = mixin(&path, 0.0, Overflow::Continue);
+            m.cache_dir = cache_dir;
+            let mut bus = MixBus::new(48_000, 3000, false);
+            bus.schedule(m, 0, 3000).unwrap();
+            let mut block = vec![0.0; 3000];
+            bus.fill(0, &mut block).unwrap();
+            block
+        };
+        let uncached = render(None);
+        let cached = render(Some(dir.path().to_path_buf()));
+        assert_eq!(uncached, cached);
+
+        let hash = sha256_file(&path).unwrap();
+        let decoded = dir.path().join(format!("_pcm_{}_48000", hash));
+        assert!(decoded.exists());
+        let modified = std::fs::metadata(&decoded).unwrap().modified().unwrap();
+        assert_eq!(render(Some(dir.path().to_path_buf())), uncached);
+        assert_eq!(
+            std::fs::metadata(&decoded).unwrap().modified().unwrap(),
+            modified
+        );
+    }
+}
@@ -1,11 +1,19 @@
-use crate::config::{Chunk, Config, NoiseSpec, ToneSpec};
+use crate::config::{self, Chunk, Config, NoiseSpec, ToneSpec};
+use crate::duck::Ducker;
+use crate::mixin::MixBus;
 use crate::noise::NoiseGenerator;
-use crate::sink::new_sink;
+use crate::sink::{self, AudioFormat, BitDepth, BitrateMode, Dither, SinkOptions, new_sink};
+use crate::subtitles::{self, SubtitleFormat};
 use crate::utils::{apply_global_fade, ease, lerp, ms_to_samples};
+use crate::verify;
 /// Does the actual audio rendering magic.
 use dasp::signal::Signal;
+use log::info;
 use std::f32::consts::TAU;
 
+/// How many samples of the mixins are streamed in at a time.
+const MIX_BLOCK: usize = 4096;
+
 fn gain_or_zero(noise: &Option<NoiseSpec>) -> f32 {
     noise.as_ref().map(|ns| ns.gain).unwrap_or(0.0)
 }
@@ -87,21 +95,88 @@ fn add_noise_and_fix_gain_in_transition(
     }
 }
 
+/// Options from the command line that change how a config is rendered.
+#[derive(Debug, Default, Clone)]
+pub struct RenderOptions {
+    /// Path to the piper binary, if it's not just `piper` in the $PATH
+    pub piper_bin: Option<String>,
+    /// Regenerate TTS even if it's already cached
+    pub force: bool,
+    /// Warn about and leave out mixins that can't be decoded, rather than failing the render
+    pub skip_broken_mixins: bool,
+    /// How many TTS clips to generate at once, or 0 for one per CPU
+    pub jobs: usize,
+    /// Where to write subtitles of the TTS, as srt, vtt or lrc depending on the extension
+    pub subtitles: Option<String>,
+    /// Overrides the config's `bit_depth`
+    pub bit_depth: Option<BitDepth>,
+    /// Overrides the config's `dither`
+    pub dither: Option<Dither>,
+    /// Overrides the config's `dither_seed`
+    pub dither_seed: Option<u64>,
+    /// Overrides the config's `bitrate`
+    pub bitrate: Option<u32>,
+    /// Overrides the config's `bitrate_mode`
+    pub bitrate_mode: Option<BitrateMode>,
+    /// Decode the output once it's written and check the beats survived encoding
+    pub verify: bool,
+    /// The output format, rather than going by the extension
+    pub format: Option<AudioFormat>,
+}
+
+impl RenderOptions {
+    pub fn get_jobs(&self) -> usize {
+        if self.jobs > 0 {
+            self.jobs
+        } else {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        }
+    }
+}
+
 /// Given a beat config and output path, write the file dynamically based on extension (WAV or
 /// FLAC).
 pub fn render(
     cfg: Config,
     out: &str,
-    piper_bin: Option<&str>,
-    force: bool,
+    opts: &RenderOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let sample_rate = cfg.get_sample_rate();
     let gain = cfg.get_gain();
     let fade_ms = cfg.get_fade_ms();
     let dt = 1.0_f32 / sample_rate as f32;
-    let chunks = cfg.create_chunks(piper_bin, force)?;
-
-    let mut sink = new_sink(out, sample_rate)?;
+    // Check this before generating anything, rather than failing after a long render.
+    if let Some(path) = &opts.subtitles {
+        SubtitleFormat::from_path(path)?;
+    }
+    let format = sink::output_format(out, opts.format)?;
+    if opts.verify && (out == sink::STDOUT || !format.info().can_decode()) {
+        return Err(
+            "--verify needs a file with a header to read back, not raw output or stdout".into(),
+        );
+    }
+    let mut sink_opts = SinkOptions {
+        sample_rate,
+        bit_depth: opts.bit_depth.or(cfg.bit_depth).unwrap_or_default(),
+        dither: opts.dither.or(cfg.dither).unwrap_or_default(),
+        dither_seed: opts.dither_seed.or(cfg.dither_seed),
+        bitrate: opts.bitrate.or(cfg.bitrate),
+        bitrate_mode: opts.bitrate_mode.or(cfg.bitrate_mode),
+        meta: cfg.meta.clone().unwrap_or_default(),
+        format: Some(format),
+        chapters: Vec::new(),
+    };
+    let chunks = cfg.create_chunks(opts.piper_bin.as_deref(), opts.force, opts.get_jobs())?;
+    sink_opts.chapters = config::chapters(&chunks);
+    let cues = opts
+        .subtitles
+        .as_ref()
+        .map(|_| subtitles::cues(&chunks, sample_rate));
+    let beat_windows = opts
+        .verify
+        .then(|| verify::beat_windows(&chunks, sample_rate));
 
     let total_samples: usize = chunks.iter().map(|c| c.samples()).sum();
     let fade_len = ms_to_samples(fade_ms, sample_rate)
@@ -112,23 +187,36 @@ pub fn render(
     let mut phase_l = 0.0_f32;
     let mut phase_r = 0.0_f32;
 
+    // Ducking state carries across chunks so the bed doesn't jump at segment boundaries.
+    let mut ducker = Ducker::new(sample_rate);
+
+    // Every mixin is scheduled up front at its absolute position in the session, then streamed in
+    // block by block, so they can play across segment boundaries without loading whole files.
+    let mut bus = MixBus::new(sample_rate, total_samples, opts.skip_broken_mixins);
+    let mut segment_start = 0usize;
+    for chunk in chunks.iter() {
+        for mixin in chunk.mixins() {
+            bus.schedule(mixin.clone(), segment_start, chunk.samples())?;
+        }
+        segment_start += chunk.samples();
+    }
+    // Only now every mixin is known to open, since creating the sink overwrites any earlier render.
+    let mut sink = new_sink(out, &sink_opts)?;
+    let mut mix_block: Vec<f32> = vec![0.0; MIX_BLOCK];
+
     let mut n_global = 0usize;
     for chunk in chunks {
-        let mut mixin_vec: Vec<f32> = vec![0.0; chunk.samples()];
-        let mixin_dest: &mut [f32] = &mut mixin_vec;
         match chunk {
             Chunk::Tone {
                 samples,
                 spec,
-                mixins,
+                duck,
+                ..
             } => {
-                for mixin in mixins {
-                    mixin.render(mixin_dest, sample_rate)?;
-                }
+                ducker.set_spec(duck);
                 let mut opt_ngen: Option<NoiseGenerator> =
                     spec.noise.map(|ns| NoiseGenerator::new(ns.color));
-                #[allow(clippy::needless_range_loop)]
-                for idx in 0..samples {
+                for _ in 0..samples {
                     let f_l = spec.carrier;
                     let f_r = spec.carrier + spec.hz;
 
@@ -138,8 +226,16 @@ pub fn render(
                     let (mut left, mut right) = ((TAU * phase_l).sin(), (TAU * phase_r).sin());
 
                     add_noise_and_fix_gain(&mut left, &mut right, &spec, &mut opt_ngen);
-                    left += mixin_dest[idx];
-                    right += mixin_dest[idx];
+                    let block_idx = n_global % MIX_BLOCK;
+                    if block_idx == 0 {
+                        bus.fill(n_global, &mut mix_block)?;
+                    }
+                    let mixed = mix_block[block_idx];
+                    let duck_gain = ducker.next_gain(mixed);
+                    left *= duck_gain;
+                    right *= duck_gain;
+                    left += mixed;
+                    right += mixed;
                     apply_global_fade(n_global, total_samples, fade_len, &mut left, &mut right);
                     // We write this out as f32 [-1.0, 1.0] because the sinks handle quantization/encoding, depending
                     // on the file type.
@@ -152,11 +248,10 @@ pub fn render(
                 from,
                 to,
                 curve,
-                mixins,
+                duck,
+                ..
             } => {
-                for mixin in mixins {
-                    mixin.render(mixin_dest, sample_rate)?;
-                }
+                ducker.set_spec(duck);
                 let mut from_ngen = from.noise.as_ref().map(|ns| NoiseGenerator::new(ns.color));
                 let mut to_ngen = to.noise.as_ref().map(|ns| NoiseGenerator::new(ns.color));
 
@@ -170,8 +265,7 @@ pub fn render(
                 }));
 
                 let mut ramp_iter = ramp;
-                #[allow(clippy::needless_range_loop)]
-                for idx in 0..samples {
+                for _ in 0..samples {
                     let t = ramp_iter.next();
 
                     let f_car = lerp(from.carrier, to.carrier, t);
@@ -195,8 +289,16 @@ pub fn render(
                         &mut opt_ngen,
                         t,
                     );
-                    left += mixin_dest[idx];
-                    right += mixin_dest[idx];
+                    let block_idx = n_global % MIX_BLOCK;
+                    if block_idx == 0 {
+                        bus.fill(n_global, &mut mix_block)?;
+                    }
+                    let mixed = mix_block[block_idx];
+                    let duck_gain = ducker.next_gain(mixed);
+                    left *= duck_gain;
+                    right *= duck_gain;
+                    left += mixed;
+                    right += mixed;
                     apply_global_fade(n_global, total_samples, fade_len, &mut left, &mut right);
                     sink.write_frame(left * gain, right * gain)?;
                     n_global += 1;
@@ -205,5 +307,12 @@ pub fn render(
         }
     }
     sink.finalize()?;
+    if let Some(windows) = beat_windows {
+        verify::verify(out, format, windows, sample_rate)?;
+    }
+    if let (Some(path), Some(cues)) = (&opts.subtitles, cues) {
+        subtitles::write(path, &cues)?;
+        info!("Wrote {} subtitles to: {:?}", cues.len(), path);
+    }
     Ok(())
 }
@@ -0,0 +1,175 @@
+/// Dither for reducing the render's f32 samples to integers. Plain rounding turns the error into
+/// distortion that follows the signal, which you can hear on quiet fade tails and low-gain tones.
+/// TPDF dither swaps that for a steady, very low hiss, and the shaped variant pushes most of that
+/// hiss up to high frequencies where it's harder to hear.
+use super::{BitDepth, SinkOptions, f32_to_i16, f32_to_i24};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dither {
+    /// Just round
+    #[default]
+    None,
+    /// Triangular dither of +/- 1 LSB
+    Tpdf,
+    /// TPDF with first order noise shaping
+    Shaped,
+}
+
+impl FromStr for Dither {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "none" => Ok(Dither::None),
+            "tpdf" => Ok(Dither::Tpdf),
+            "shaped" => Ok(Dither::Shaped),
+            other => Err(format!(
+                "unsupported dither {:?}, use none, tpdf or shaped",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Dither {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dither::None => write!(f, "none"),
+            Dither::Tpdf => write!(f, "tpdf"),
+            Dither::Shaped => write!(f, "shaped"),
+        }
+    }
+}
+
+/// Turns f32 samples into integers at the sink's bit depth, with its dither.
+pub struct Quantizer {
+    bit_depth: BitDepth,
+    dither: Dither,
+    /// Full scale in LSBs, eg: 32767 for 16-bit
+    scale: f32,
+    rng: StdRng,
+    /// The last quantization error of each channel, for noise shaping
+    error: [f32; 2],
+}
+
+impl Quantizer {
+    /// With a `dither_seed` the dither is the same on every run, so renders can be compared.
+    pub fn new(opts: &SinkOptions) -> Self {
+        let rng = match opts.dither_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        Quantizer {
+            bit_depth: opts.bit_depth,
+            dither: opts.dither,
+            scale: ((1_i32 << (opts.bit_depth.bits().min(24) - 1)) - 1) as f32,
+            rng,
+            error: [0.0; 2],
+        }
+    }
+
+    /// One sample in [-1.0, 1.0] of a channel (0 is left, 1 is right) as an integer sample.
+    pub fn quantize(&mut self, x: f32, channel: usize) -> i32 {
+        let tpdf = |rng: &mut StdRng| rng.random::<f32>() - rng.random::<f32>();
+        match self.dither {
+            Dither::None => match self.bit_depth {
+                BitDepth::Int24 => f32_to_i24(x),
+                _ => f32_to_i16(x) as i32,
+            },
+            Dither::Tpdf => {
+                let v = x.clamp(-1.0, 1.0) * self.scale + tpdf(&mut self.rng);
+                v.round().clamp(-self.scale, self.scale) as i32
+            }
+            Dither::Shaped => {
+                // Feeding back the last error shapes it by (1 - z^-1), a first order high-pass.
+                let v = x.clamp(-1.0, 1.0) * self.scale - self.error[channel];
+                let q = (v + tpdf(&mut self.rng)).round();
+                self.error[channel] = q - v;
+                q.clamp(-self.scale, self.scale) as i32
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quantizer(dither: Dither, seed: Option<u64>) -> Quantizer {
+        Quantizer::new(&SinkOptions {
+            sample_rate: 48000,
+            bit_depth: BitDepth::Int16,
+            dither,
+            dither_seed: seed,
+            ..Default::default()
+        })
+    }
+
+    /// A sine far quieter than 1 LSB, like the very end of a fade out.
+    fn quiet_sine(q: &mut Quantizer, n: usize) -> Vec<i32> {
+        (0..n)
+            .map(|i| {
+                let x = 0.4 / 32767.0 * (i as f32 * 0.05).sin();
+                q.quantize(x, 0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_no_dither_rounds() {
+        let mut q = quantizer(Dither::None, None);
+        assert_eq!(q.quantize(0.5, 0), f32_to_i16(0.5) as i32);
+        // Below half an LSB, plain rounding loses it completely.
+        assert!(quiet_sine(&mut q, 1000).iter().all(|s| *s == 0));
+    }
+
+    #[test]
+    fn test_tpdf_keeps_quiet_signal() {
+        let mut q = quantizer(Dither::Tpdf, Some(7));
+        let out = quiet_sine(&mut q, 20000);
+        assert!(out.iter().all(|s| s.abs() <= 2));
+        assert!(out.iter().any(|s| *s != 0));
+        // On average it still follows the signal.
+        let corr: f32 = out
+            .iter()
+            .enumerate()
+            .map(|(i, s)| *s as f32 * (i as f32 * 0.05).sin())
+            .sum();
+        assert!(corr > 0.0);
+    }
+
+    #[test]
+    fn test_seed_is_deterministic() {
+        for dither in [Dither::Tpdf, Dither::Shaped] {
+            let a = quiet_sine(&mut quantizer(dither, Some(42)), 500);
+            let b = quiet_sine(&mut quantizer(dither, Some(42)), 500);
+            let c = quiet_sine(&mut quantizer(dither, Some(43)), 500);
+            assert_eq!(a, b);
+            assert_ne!(a, c);
+        }
+    }
+
+    #[test]
+    fn test_shaped_noise_is_mostly_high() {
+        // Dithered silence is just the noise. Shaping it makes neighbouring samples anti-correlated,
+        // which is a high-pass: their differences are bigger than for plain TPDF.
+        let diff_power = |out: &[i32]| -> f32 {
+            out.windows(2)
+                .map(|w| ((w[1] - w[0]) as f32).powi(2))
+                .sum::<f32>()
+                / out.iter().map(|s| (*s as f32).powi(2)).sum::<f32>()
+        };
+        let silence = |dither| -> Vec<i32> {
+            let mut q = quantizer(dither, Some(1));
+            (0..20000).map(|_| q.quantize(0.0, 0)).collect()
+        };
+        assert!(diff_power(&silence(Dither::Shaped)) > diff_power(&silence(Dither::Tpdf)));
+        assert_eq!("shaped".parse::<Dither>(), Ok(Dither::Shaped));
+    }
+}
@@ -1,100 +1,250 @@
-/// Flac generation is way messier here, and requires lib-flac:
-///   Ubuntu/Debian: sudo apt install libflac-dev
-///   Fedora/RHEL: sudo dnf install flac-devel
-///   Arch: sudo pacman -S flac
+/// FLAC output, encoded in pure Rust by flacenc and read back for `--verify` by claxon, so there's
+/// nothing to install.
 ///
-/// This code is a bit messy with an unsafe block, but it works.
-use super::{AudioSink, f32_to_i16};
-use flac_bound::{FlacEncoder, WriteWrapper};
-use std::{error::Error, fs::File, path::Path, ptr::NonNull};
+/// flacenc wants the whole signal up front, which for an hour long session is gigabytes, so this
+/// feeds it a block at a time and writes each frame as it goes. The STREAMINFO header at the start
+/// of the file can only be filled in at the end, once the length and MD5 are known, so a blank one
+/// of the same size is written first and overwritten on finalize.
+use super::{AudioSink, BitDepth, Quantizer, SinkOptions, vorbis_comment_header};
+use flacenc::bitsink::ByteSink;
+use flacenc::component::{BitRepr, MetadataBlockData, Stream};
+use flacenc::config;
+use flacenc::error::{Verified, Verify};
+use flacenc::source::{Context, Fill, FrameBuf};
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Frames per FLAC block, what the reference encoder uses at its default level
+const BLOCK: usize = 4096;
+/// The metadata block type for tags
+const VORBIS_COMMENT: u8 = 4;
+
+fn flac_error(what: &str, err: impl std::fmt::Display) -> Box<dyn Error> {
+    format!("flac: {} failed: {}", what, err).into()
+}
+
+/// The "fLaC" marker and metadata blocks, without any frames
+fn header_bytes(stream: &Stream) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut sink = ByteSink::new();
+    stream
+        .write(&mut sink)
+        .map_err(|e| flac_error("writing the header", e))?;
+    Ok(sink.into_inner())
+}
 
 pub struct FlacSink {
-    file_ptr: NonNull<File>,
-    wrapper_ptr: NonNull<WriteWrapper<'static>>,
-    enc: Option<FlacEncoder<'static>>,
+    writer: BufWriter<File>,
+    config: Verified<config::Encoder>,
+    /// Only used for its header, frames are written out rather than added to it.
+    stream: Stream,
+    framebuf: FrameBuf,
+    /// Tracks the frame number, sample count and MD5 of everything encoded
+    context: Context,
+    /// Interleaved samples waiting for a full block
     buf: Vec<i32>,
-    reclaimed: bool,
+    quantizer: Quantizer,
 }
 
 impl FlacSink {
-    pub fn create(out: &str, sample_rate: u32) -> Result<Box<dyn AudioSink>, Box<dyn Error>> {
-        let file_box = Box::new(File::create(Path::new(out))?);
-        let file_ptr = Box::into_raw(file_box);
-        let file_static: &'static mut File = unsafe { &mut *file_ptr };
-
-        let wrapper_box = Box::new(WriteWrapper(file_static));
-        let wrapper_ptr = Box::into_raw(wrapper_box);
-        let wrapper_static: &'static mut WriteWrapper<'static> = unsafe { &mut *wrapper_ptr };
-
-        // Build encoder will borrow the static lifetime here:
-        let enc = FlacEncoder::new()
-            .unwrap()
-            .channels(2)
-            .sample_rate(sample_rate)
-            .bits_per_sample(16)
-            .compression_level(5)
-            .init_write(wrapper_static)
-            .unwrap();
+    pub fn create(out: &str, opts: &SinkOptions) -> Result<Box<dyn AudioSink>, Box<dyn Error>> {
+        // FLAC only stores integer samples.
+        if opts.bit_depth == BitDepth::Float32 {
+            return Err("flac: 32-bit float isn't supported, use a bit depth of 16 or 24".into());
+        }
+        let bits = opts.bit_depth.bits() as usize;
+        let mut config = config::Encoder::default();
+        config.block_size = BLOCK;
+        let config = config
+            .into_verified()
+            .map_err(|(_, e)| flac_error("configuring the encoder", e))?;
+        let mut stream = Stream::new(opts.sample_rate as usize, 2, bits)
+            .map_err(|e| flac_error("setting up the stream", e))?;
+        stream
+            .stream_info_mut()
+            .set_block_sizes(BLOCK, BLOCK)
+            .map_err(|e| flac_error("setting the block size", e))?;
+        let comments = MetadataBlockData::new_unknown(VORBIS_COMMENT, &vorbis_comment_header(opts))
+            .map_err(|e| flac_error("adding the tags", e))?;
+        stream.add_metadata_block(comments);
 
+        let mut writer = BufWriter::new(File::create(Path::new(out))?);
+        writer.write_all(&header_bytes(&stream)?)?;
         Ok(Box::new(FlacSink {
-            file_ptr: NonNull::new(file_ptr).unwrap(),
-            wrapper_ptr: NonNull::new(wrapper_ptr).unwrap(),
-            enc: Some(enc),
-            buf: Vec::with_capacity(4096),
-            reclaimed: false,
+            writer,
+            config,
+            stream,
+            framebuf: FrameBuf::with_size(2, BLOCK)
+                .map_err(|e| flac_error("allocating a block", e))?,
+            context: Context::new(bits, 2),
+            buf: Vec::with_capacity(BLOCK * 2),
+            quantizer: Quantizer::new(opts),
         }))
     }
 
-    // SAFETY: We only call this after the encoder has been finished and won't touch the writer anymore.
-    fn reclaim_leaks(&mut self) {
-        if self.reclaimed {
-            return;
-        }
-        unsafe {
-            let _wrapper: Box<WriteWrapper<'static>> = Box::from_raw(self.wrapper_ptr.as_ptr());
-            let _file: Box<File> = Box::from_raw(self.file_ptr.as_ptr());
-        }
-        self.reclaimed = true;
+    fn encode_block(&mut self) -> Result<(), Box<dyn Error>> {
+        // A short last block is fine, the frame header says how long it is.
+        self.framebuf.resize(self.buf.len() / 2);
+        (&mut self.framebuf, &mut self.context)
+            .fill_interleaved(&self.buf)
+            .map_err(|e| flac_error("reading samples", e))?;
+        let frame_number = self.context.current_frame_number().unwrap_or(0);
+        let frame = flacenc::encode_fixed_size_frame(
+            &self.config,
+            &self.framebuf,
+            frame_number,
+            self.stream.stream_info(),
+        )
+        .map_err(|e| flac_error("encoding a frame", e))?;
+        self.stream.stream_info_mut().update_frame_info(&frame);
+
+        let mut sink = ByteSink::new();
+        frame
+            .write(&mut sink)
+            .map_err(|e| flac_error("writing a frame", e))?;
+        self.writer.write_all(sink.as_slice())?;
+        self.buf.clear();
+        Ok(())
     }
 }
 
 impl AudioSink for FlacSink {
     fn write_frame(&mut self, l: f32, r: f32) -> Result<(), Box<dyn Error>> {
-        self.buf.clear();
-        self.buf.push(f32_to_i16(l) as i32);
-        self.buf.push(f32_to_i16(r) as i32);
+        self.buf.push(self.quantizer.quantize(l, 0));
+        self.buf.push(self.quantizer.quantize(r, 1));
+        if self.buf.len() == BLOCK * 2 {
+            self.encode_block()?;
+        }
+        Ok(())
+    }
 
-        let enc = self
-            .enc
-            .as_mut()
-            .ok_or::<Box<dyn Error>>("flac: encoder not initialized".into())?;
+    fn finalize(mut self: Box<Self>) -> Result<(), Box<dyn Error>> {
+        if !self.buf.is_empty() {
+            self.encode_block()?;
+        }
+        let info = self.stream.stream_info_mut();
+        info.set_md5_digest(&self.context.md5_digest());
+        info.set_total_samples(self.context.total_samples());
+        let header = header_bytes(&self.stream)?;
 
-        enc.process_interleaved(&self.buf, 1)
-            .map_err(|_| "flac: process_interleaved failed".into())
+        let mut file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header)?;
+        file.flush()?;
+        Ok(())
     }
+}
 
-    fn finalize(mut self: Box<Self>) -> Result<(), Box<dyn Error>> {
-        if let Some(enc) = self.enc.take() {
-            let res = match enc.finish() {
-                Ok(_) => Ok(()),
-                Err(enc) => Err(format!("FLAC finalize failed: {:?}", enc.state()).into()),
-            };
-            self.reclaim_leaks();
-            res
-        } else {
-            // Already finished or not initialized?
-            self.reclaim_leaks();
-            Ok(())
+/// Read a FLAC back as f32 frames in [-1.0, 1.0].
+pub fn decode(path: &str, on_frame: &mut dyn FnMut(f32, f32)) -> Result<u32, Box<dyn Error>> {
+    let mut reader = claxon::FlacReader::open(Path::new(path))?;
+    let info = reader.streaminfo();
+    let channels = info.channels as usize;
+    let scale = (1_i64 << (info.bits_per_sample - 1)) as f32;
+    let mut frame = vec![0.0_f32; channels];
+    for (i, s) in reader.samples().enumerate() {
+        frame[i % channels] = s? as f32 / scale;
+        if i % channels == channels - 1 {
+            on_frame(frame[0], frame[channels.min(2) - 1]);
         }
     }
+    Ok(info.sample_rate)
 }
 
-impl Drop for FlacSink {
-    fn drop(&mut self) {
-        // ...if the user forgot to call finalize:
-        if let Some(enc) = self.enc.take() {
-            let _ = enc.finish();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{Chapter, Metadata};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_flac_stream_info() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("out.flac");
+        let opts = SinkOptions {
+            sample_rate: 44100,
+            bit_depth: BitDepth::Int24,
+            ..Default::default()
+        };
+        let mut sink = FlacSink::create(path.to_str().unwrap(), &opts).unwrap();
+        // Two full blocks and a short one
+        let n = BLOCK * 2 + 1000;
+        for i in 0..n {
+            let x = 0.5 * (i as f32 * 0.05).sin();
+            sink.write_frame(x, -x).unwrap();
         }
-        self.reclaim_leaks();
+        sink.finalize().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(&bytes[..4], b"fLaC");
+        // STREAMINFO starts after the marker and a 4 byte block header. Bytes 10 to 18 of it pack
+        // the sample rate (20 bits), channels - 1 (3), bits - 1 (5) and total samples (36).
+        let info = &bytes[8..42];
+        let packed = u64::from_be_bytes(info[10..18].try_into().unwrap());
+        assert_eq!(packed >> 44, 44100);
+        assert_eq!((packed >> 41) & 0x7, 1);
+        assert_eq!((packed >> 36) & 0x1f, 23);
+        assert_eq!(packed & 0xf_ffff_ffff, n as u64);
+        assert_ne!(&info[18..34], &[0u8; 16], "the MD5 should be filled in");
+        // A sine compresses well below the 6 bytes a frame it'd take raw.
+        assert!(bytes.len() < n * 6 / 2);
+    }
+
+    #[test]
+    fn test_flac_round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("out.flac");
+        let path = path.to_str().unwrap();
+        let opts = SinkOptions {
+            sample_rate: 48000,
+            meta: Metadata {
+                artist: Some("opengate".to_string()),
+                ..Default::default()
+            },
+            chapters: vec![Chapter {
+                start: 0,
+                name: "Induction".to_string(),
+            }],
+            ..Default::default()
+        };
+        let input: Vec<(f32, f32)> = (0..10_000)
+            .map(|i| {
+                let x = 0.5 * (i as f32 * 0.05).sin();
+                (x, -x * 0.25)
+            })
+            .collect();
+        let mut sink = FlacSink::create(path, &opts).unwrap();
+        for (l, r) in input.iter() {
+            sink.write_frame(*l, *r).unwrap();
+        }
+        sink.finalize().unwrap();
+
+        // claxon reads the rewritten STREAMINFO to know how to decode the frames.
+        let mut decoded = Vec::new();
+        let sr = decode(path, &mut |l, r| decoded.push((l, r))).unwrap();
+        assert_eq!(sr, 48000);
+        assert_eq!(decoded.len(), input.len());
+        let reader = claxon::FlacReader::open(path).unwrap();
+        assert_eq!(reader.get_tag("ARTIST").collect::<Vec<_>>(), ["opengate"]);
+        assert_eq!(reader.get_tag("CHAPTER001NAME").next(), Some("Induction"));
+        // Lossless, so only off by the 16-bit rounding
+        for ((l, r), (dl, dr)) in input.iter().zip(decoded.iter()) {
+            assert!((l - dl).abs() < 1e-4 && (r - dr).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_flac_rejects_float() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("out.flac");
+        let opts = SinkOptions {
+            sample_rate: 44100,
+            bit_depth: BitDepth::Float32,
+            ..Default::default()
+        };
+        assert!(FlacSink::create(path.to_str().unwrap(), &opts).is_err());
     }
 }
@@ -1,11 +1,257 @@
 use log::warn;
+use serde::Deserialize;
 use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
 
+/// An output format, whether or not this build has it. See `SINKS` for what each one is.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AudioFormat {
     Wav,
-    #[allow(dead_code)]
     Flac,
+    Opus,
+    Vorbis,
+    Mp3,
+    Raw,
+}
+
+impl AudioFormat {
+    /// Its entry in the registry
+    pub fn info(&self) -> &'static SinkInfo {
+        SINKS
+            .iter()
+            .find(|info| info.format == *self)
+            .expect("every format is registered")
+    }
+}
+
+/// Accepts the name or any of the extensions, eg: `vorbis` or `ogg`.
+impl FromStr for AudioFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().trim_start_matches('.').to_ascii_lowercase();
+        SINKS
+            .iter()
+            .find(|info| info.name == s || info.extensions.contains(&s.as_str()))
+            .map(|info| info.format)
+            .ok_or_else(|| {
+                format!(
+                    "unknown output format {:?}, use one of: {}",
+                    s,
+                    format_names()
+                )
+            })
+    }
+}
+
+impl fmt::Display for AudioFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.info().name)
+    }
+}
+
+/// The sample format to write, eg: 24-bit to export a master for further editing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(try_from = "BitDepthValue")]
+pub enum BitDepth {
+    #[default]
+    Int16,
+    Int24,
+    Float32,
+}
+
+/// How a bit depth is written in configs, eg: `bit_depth: 24` or `bit_depth: 32f`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BitDepthValue {
+    Number(u32),
+    Text(String),
+}
+
+impl TryFrom<BitDepthValue> for BitDepth {
+    type Error = String;
+
+    fn try_from(value: BitDepthValue) -> Result<Self, Self::Error> {
+        match value {
+            BitDepthValue::Number(n) => n.to_string().parse(),
+            BitDepthValue::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl FromStr for BitDepth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "16" => Ok(BitDepth::Int16),
+            "24" => Ok(BitDepth::Int24),
+            "32f" | "32F" => Ok(BitDepth::Float32),
+            other => Err(format!(
+                "unsupported bit depth {:?}, use 16, 24 or 32f",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BitDepth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitDepth::Int16 => write!(f, "16"),
+            BitDepth::Int24 => write!(f, "24"),
+            BitDepth::Float32 => write!(f, "32f"),
+        }
+    }
+}
+
+impl BitDepth {
+    pub fn bits(&self) -> u16 {
+        match self {
+            BitDepth::Int16 => 16,
+            BitDepth::Int24 => 24,
+            BitDepth::Float32 => 32,
+        }
+    }
+}
+
+/// How the lossy formats spend their bitrate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BitrateMode {
+    /// Constant, which the most players can seek in accurately
+    Cbr,
+    /// Variable, which spends more on the hard parts for the same size
+    Vbr,
+}
+
+impl FromStr for BitrateMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "cbr" => Ok(BitrateMode::Cbr),
+            "vbr" => Ok(BitrateMode::Vbr),
+            other => Err(format!(
+                "unsupported bitrate mode {:?}, use cbr or vbr",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BitrateMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitrateMode::Cbr => write!(f, "cbr"),
+            BitrateMode::Vbr => write!(f, "vbr"),
+        }
+    }
+}
+
+/// Tags to write into the output, where the format has somewhere to put them.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Metadata {
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub artist: Option<String>,
+    #[serde(default)]
+    pub album: Option<String>,
+    #[serde(default)]
+    pub comment: Option<String>,
+    #[serde(default)]
+    pub license: Option<String>,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.artist.is_none()
+            && self.album.is_none()
+            && self.comment.is_none()
+            && self.license.is_none()
+    }
+}
+
+/// A named point in the output that players can jump to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chapter {
+    /// In frames from the start
+    pub start: usize,
+    pub name: String,
+}
+
+/// A chapter's start as HH:MM:SS.mmm
+fn chapter_time(start: usize, sample_rate: u32) -> String {
+    let ms = start as u64 * 1000 / sample_rate as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+/// The tags and chapters as Vorbis comments, for FLAC, Opus and Ogg Vorbis. Chapters use the
+/// CHAPTER001 and CHAPTER001NAME pairs that players like mpv, VLC and foobar2000 read.
+pub fn vorbis_comments(opts: &SinkOptions) -> Vec<(String, String)> {
+    let meta = &opts.meta;
+    let mut comments: Vec<(String, String)> = [
+        ("TITLE", &meta.title),
+        ("ARTIST", &meta.artist),
+        ("ALBUM", &meta.album),
+        ("COMMENT", &meta.comment),
+        ("LICENSE", &meta.license),
+    ]
+    .iter()
+    .filter_map(|(key, value)| Some((key.to_string(), (*value).clone()?)))
+    .collect();
+    for (i, chapter) in opts.chapters.iter().enumerate() {
+        let key = format!("CHAPTER{:03}", i + 1);
+        comments.push((key.clone(), chapter_time(chapter.start, opts.sample_rate)));
+        comments.push((key + "NAME", chapter.name.clone()));
+    }
+    comments
+}
+
+/// The body of a Vorbis comment header: a vendor string then each comment as KEY=value, with
+/// little-endian lengths. FLAC stores it as a metadata block and Opus after "OpusTags".
+pub fn vorbis_comment_header(opts: &SinkOptions) -> Vec<u8> {
+    fn push_str(out: &mut Vec<u8>, s: &str) {
+        out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        out.extend_from_slice(s.as_bytes());
+    }
+    let comments = vorbis_comments(opts);
+    let mut out = Vec::new();
+    push_str(&mut out, concat!("opengate ", env!("CARGO_PKG_VERSION")));
+    out.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for (key, value) in comments.iter() {
+        push_str(&mut out, &format!("{}={}", key, value));
+    }
+    out
+}
+
+/// Everything a sink needs to know about the audio it's writing.
+#[derive(Debug, Clone, Default)]
+pub struct SinkOptions {
+    pub sample_rate: u32,
+    pub bit_depth: BitDepth,
+    /// Dither when reducing to 16 or 24-bit
+    pub dither: Dither,
+    /// Makes the dither the same on every run
+    pub dither_seed: Option<u64>,
+    /// Target bitrate in kbps for the lossy formats, or their own default
+    pub bitrate: Option<u32>,
+    /// Constant or variable bitrate, or the format's own default
+    pub bitrate_mode: Option<BitrateMode>,
+    /// The format to write, rather than going by the extension
+    pub format: Option<AudioFormat>,
+    pub meta: Metadata,
+    /// Where each segment starts
+    pub chapters: Vec<Chapter>,
 }
 
 pub trait AudioSink {
@@ -22,59 +268,249 @@ pub fn f32_to_i16(x: f32) -> i16 {
     y as i16
 }
 
+/// For conversions from f32 to a 24-bit sample, held in an i32
+#[inline]
+pub fn f32_to_i24(x: f32) -> i32 {
+    const MAX: f32 = ((1 << 23) - 1) as f32;
+    (x.clamp(-1.0, 1.0) * MAX).round() as i32
+}
+
+pub use dither::{Dither, Quantizer};
+pub use stream::StreamSink;
 pub use wav::WavSink;
+mod dither;
+mod stream;
 mod wav;
 
+/// The output path that means stdout
+pub const STDOUT: &str = "-";
+
 #[cfg(feature = "flac")]
 pub use flac::FlacSink;
 #[cfg(feature = "flac")]
 mod flac;
 
-pub fn new_sink(out: &str, sample_rate: u32) -> Result<Box<dyn AudioSink>, Box<dyn Error>> {
-    match detect_format_from_ext(out) {
-        AudioFormat::Wav => WavSink::create(out, sample_rate),
+#[cfg(feature = "opus")]
+pub use opus::OpusSink;
+#[cfg(feature = "opus")]
+mod opus;
+
+#[cfg(feature = "vorbis")]
+pub use vorbis::VorbisSink;
+#[cfg(feature = "vorbis")]
+mod vorbis;
+
+#[cfg(feature = "mp3")]
+pub use mp3::Mp3Sink;
+#[cfg(feature = "mp3")]
+mod mp3;
 
-        #[cfg(feature = "flac")]
-        AudioFormat::Flac => FlacSink::create(out, sample_rate),
+type CreateFn = fn(&str, &SinkOptions) -> Result<Box<dyn AudioSink>, Box<dyn Error>>;
+type DecodeFn = fn(&str, &mut dyn FnMut(f32, f32)) -> Result<u32, Box<dyn Error>>;
 
-        #[allow(dead_code)]
-        #[cfg(not(feature = "flac"))]
-        AudioFormat::Flac => Err("FLAC support not enabled, build with `--features flac`".into()),
+/// How to write a format, and read it back for `--verify`.
+pub struct Codec {
+    create: CreateFn,
+    /// None if there's no header to say what the samples are
+    decode: Option<DecodeFn>,
+}
+
+/// A format the CLI knows about. `codec` is None when the feature it needs was left out of the
+/// build, so it can still be named in errors and `--list-formats`.
+pub struct SinkInfo {
+    pub format: AudioFormat,
+    /// What `--format` takes
+    pub name: &'static str,
+    /// Picked by these when there's no `--format`
+    pub extensions: &'static [&'static str],
+    pub description: &'static str,
+    /// The cargo feature it needs, if it isn't always built
+    pub feature: Option<&'static str>,
+    pub codec: Option<Codec>,
+}
+
+impl SinkInfo {
+    pub fn is_built(&self) -> bool {
+        self.codec.is_some()
     }
+
+    pub fn can_decode(&self) -> bool {
+        self.codec.as_ref().is_some_and(|c| c.decode.is_some())
+    }
+}
+
+/// Every output format, in the order `--list-formats` shows them.
+pub const SINKS: &[SinkInfo] = &[
+    SinkInfo {
+        format: AudioFormat::Wav,
+        name: "wav",
+        extensions: &["wav"],
+        description: "uncompressed WAV",
+        feature: None,
+        codec: Some(Codec {
+            create: WavSink::create,
+            decode: Some(wav::decode),
+        }),
+    },
+    SinkInfo {
+        format: AudioFormat::Flac,
+        name: "flac",
+        extensions: &["flac"],
+        description: "FLAC, compressed but loss-less",
+        feature: Some("flac"),
+        codec: FLAC,
+    },
+    SinkInfo {
+        format: AudioFormat::Opus,
+        name: "opus",
+        extensions: &["opus"],
+        description: "Ogg Opus, lossy",
+        feature: Some("opus"),
+        codec: OPUS,
+    },
+    SinkInfo {
+        format: AudioFormat::Vorbis,
+        name: "vorbis",
+        extensions: &["ogg", "oga"],
+        description: "Ogg Vorbis, lossy",
+        feature: Some("vorbis"),
+        codec: VORBIS,
+    },
+    SinkInfo {
+        format: AudioFormat::Mp3,
+        name: "mp3",
+        extensions: &["mp3"],
+        description: "MP3, lossy",
+        feature: Some("mp3"),
+        codec: MP3,
+    },
+    SinkInfo {
+        format: AudioFormat::Raw,
+        name: "raw",
+        extensions: &["raw", "pcm"],
+        description: "bare little-endian PCM samples with no header",
+        feature: None,
+        codec: Some(Codec {
+            create: StreamSink::create_raw,
+            decode: None,
+        }),
+    },
+];
+
+#[cfg(feature = "flac")]
+const FLAC: Option<Codec> = Some(Codec {
+    create: FlacSink::create,
+    decode: Some(flac::decode),
+});
+#[cfg(not(feature = "flac"))]
+const FLAC: Option<Codec> = None;
+
+#[cfg(feature = "opus")]
+const OPUS: Option<Codec> = Some(Codec {
+    create: OpusSink::create,
+    decode: Some(opus::decode),
+});
+#[cfg(not(feature = "opus"))]
+const OPUS: Option<Codec> = None;
+
+#[cfg(feature = "vorbis")]
+const VORBIS: Option<Codec> = Some(Codec {
+    create: VorbisSink::create,
+    decode: Some(vorbis::decode),
+});
+#[cfg(not(feature = "vorbis"))]
+const VORBIS: Option<Codec> = None;
+
+#[cfg(feature = "mp3")]
+const MP3: Option<Codec> = Some(Codec {
+    create: Mp3Sink::create,
+    decode: Some(mp3::decode),
+});
+#[cfg(not(feature = "mp3"))]
+const MP3: Option<Codec> = None;
+
+/// eg: "wav, flac, opus, vorbis, mp3 or raw"
+fn format_names() -> String {
+    let names: Vec<&str> = SINKS.iter().map(|info| info.name).collect();
+    let (last, rest) = names.split_last().expect("there are formats");
+    format!("{} or {}", rest.join(", "), last)
 }
 
-/// Infer audio file format by file extension.
-fn detect_format_from_ext(out: &str) -> AudioFormat {
-    use std::path::Path;
-    match Path::new(out)
+/// The lowercase extension of a path, or "" if it has none
+fn extension(out: &str) -> String {
+    std::path::Path::new(out)
         .extension()
         .and_then(|s| s.to_str())
         .unwrap_or("")
         .to_ascii_lowercase()
-        .as_str()
-    {
-        #[cfg(feature = "flac")]
-        "flac" => AudioFormat::Flac,
-
-        #[allow(dead_code)]
-        #[cfg(not(feature = "flac"))]
-        "flac" => {
-            warn!(
-                "File extension was flac, however flac feature was not enabled during build. Using WAV."
-            );
-            AudioFormat::Wav
+}
+
+/// Work out what to write to `out`: the `format` if one's given, or else whatever the extension
+/// says. Errors if it can't tell, or if this build doesn't have that format.
+pub fn output_format(
+    out: &str,
+    format: Option<AudioFormat>,
+) -> Result<AudioFormat, Box<dyn Error>> {
+    let ext = extension(out);
+    let format = match format {
+        Some(format) => {
+            if out != STDOUT && !ext.is_empty() && !format.info().extensions.contains(&ext.as_str())
+            {
+                warn!("Writing {} to {:?}, despite its extension.", format, out);
+            }
+            format
         }
+        // There's nothing to go on, but WAV is what's most likely to be read from a pipe.
+        None if out == STDOUT => AudioFormat::Wav,
+        None => SINKS
+            .iter()
+            .find(|info| info.extensions.contains(&ext.as_str()))
+            .map(|info| info.format)
+            .ok_or_else(|| {
+                format!(
+                    "can't tell the output format of {:?} from its extension, pass --format {}",
+                    out,
+                    format_names()
+                )
+            })?,
+    };
+    let info = format.info();
+    if let (false, Some(feature)) = (info.is_built(), info.feature) {
+        return Err(format!(
+            "{} output isn't in this build of opengate, rebuild it with `--features {}`",
+            format, feature
+        )
+        .into());
+    }
+    if out == STDOUT && !matches!(format, AudioFormat::Wav | AudioFormat::Raw) {
+        return Err(format!("only wav or raw can be streamed to stdout, not {}", format).into());
+    }
+    Ok(format)
+}
 
-        "wav" => AudioFormat::Wav,
+pub fn new_sink(out: &str, opts: &SinkOptions) -> Result<Box<dyn AudioSink>, Box<dyn Error>> {
+    let format = output_format(out, opts.format)?;
+    if out == STDOUT {
+        return StreamSink::stdout(opts, format == AudioFormat::Raw);
+    }
+    let codec = format
+        .info()
+        .codec
+        .as_ref()
+        .expect("output_format checks it's built");
+    (codec.create)(out, opts)
+}
 
-        // In the future, let's error out. But WAV for now.
-        other => {
-            warn!(
-                "File extension was {:?}, writing WAV (only wav and flav supported).",
-                other
-            );
-            AudioFormat::Wav
-        }
+/// Decode a rendered file, passing each stereo frame to `on_frame`, and return its sample rate.
+/// This streams, so checking an hour long render doesn't need it all in memory.
+pub fn decode(
+    out: &str,
+    format: AudioFormat,
+    on_frame: &mut dyn FnMut(f32, f32),
+) -> Result<u32, Box<dyn Error>> {
+    match format.info().codec.as_ref().and_then(|c| c.decode) {
+        Some(decode) => decode(out, on_frame),
+        None => Err(format!("can't decode {} output", format).into()),
     }
 }
 
@@ -83,7 +519,108 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_detect_format_from_exc() {
-        assert_eq!(detect_format_from_ext("foo.wav"), AudioFormat::Wav);
+    fn test_output_format() {
+        assert_eq!(output_format("foo.WAV", None).unwrap(), AudioFormat::Wav);
+        assert_eq!(output_format("foo.pcm", None).unwrap(), AudioFormat::Raw);
+        assert_eq!(output_format("-", None).unwrap(), AudioFormat::Wav);
+        let raw = Some(AudioFormat::Raw);
+        assert_eq!(output_format("foo.wav", raw).unwrap(), AudioFormat::Raw);
+        let err = output_format("session.aac", None).unwrap_err().to_string();
+        assert!(err.contains("pass --format wav, flac"), "{}", err);
+        assert!(output_format("session", None).is_err());
+        assert!(output_format("-", Some(AudioFormat::Mp3)).is_err());
+        // Whether it's built or not, every format has a name the errors can use.
+        #[cfg(not(feature = "mp3"))]
+        assert!(
+            output_format("foo.mp3", None)
+                .unwrap_err()
+                .to_string()
+                .contains("--features mp3")
+        );
+    }
+
+    #[test]
+    fn test_audio_format_names() {
+        assert_eq!("ogg".parse::<AudioFormat>(), Ok(AudioFormat::Vorbis));
+        assert_eq!("Vorbis".parse::<AudioFormat>(), Ok(AudioFormat::Vorbis));
+        assert_eq!(".flac".parse::<AudioFormat>(), Ok(AudioFormat::Flac));
+        assert!("aac".parse::<AudioFormat>().is_err());
+        for info in SINKS.iter() {
+            assert_eq!(info.name.parse::<AudioFormat>(), Ok(info.format));
+            assert_eq!(info.format.to_string(), info.name);
+        }
+    }
+
+    #[test]
+    fn test_bit_depth() {
+        assert_eq!("24".parse::<BitDepth>(), Ok(BitDepth::Int24));
+        assert_eq!("32f".parse::<BitDepth>(), Ok(BitDepth::Float32));
+        assert!("32".parse::<BitDepth>().is_err());
+        let depths: Vec<BitDepth> = serde_yaml::from_str("[16, 24, 32f]").unwrap();
+        assert_eq!(
+            depths,
+            vec![BitDepth::Int16, BitDepth::Int24, BitDepth::Float32]
+        );
+        assert!(serde_yaml::from_str::<BitDepth>("8").is_err());
+    }
+
+    #[test]
+    fn test_bitrate_mode() {
+        assert_eq!("VBR".parse::<BitrateMode>(), Ok(BitrateMode::Vbr));
+        assert!("abr".parse::<BitrateMode>().is_err());
+    }
+
+    #[test]
+    fn test_vorbis_comments() {
+        let meta: Metadata = serde_yaml::from_str("title: Body Scan").unwrap();
+        assert_eq!(meta.title.as_deref(), Some("Body Scan"));
+        assert!(!meta.is_empty());
+        assert!(Metadata::default().is_empty());
+
+        let opts = SinkOptions {
+            sample_rate: 1000,
+            meta: Metadata {
+                title: Some("Body Scan".to_string()),
+                license: Some("CC-BY-4.0".to_string()),
+                ..Default::default()
+            },
+            chapters: vec![
+                Chapter {
+                    start: 0,
+                    name: "Induction".to_string(),
+                },
+                Chapter {
+                    start: 3_723_456,
+                    name: "Return".to_string(),
+                },
+            ],
+            ..Default::default()
+        };
+        let comments: Vec<String> = vorbis_comments(&opts)
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        assert_eq!(
+            comments,
+            vec![
+                "TITLE=Body Scan",
+                "LICENSE=CC-BY-4.0",
+                "CHAPTER001=00:00:00.000",
+                "CHAPTER001NAME=Induction",
+                "CHAPTER002=01:02:03.456",
+                "CHAPTER002NAME=Return",
+            ]
+        );
+        let header = vorbis_comment_header(&opts);
+        let vendor_len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let count = &header[4 + vendor_len..8 + vendor_len];
+        assert_eq!(u32::from_le_bytes(count.try_into().unwrap()), 6);
+    }
+
+    #[test]
+    fn test_f32_to_i24() {
+        assert_eq!(f32_to_i24(1.0), 8_388_607);
+        assert_eq!(f32_to_i24(-2.0), -8_388_607);
+        assert_eq!(f32_to_i24(0.5), 4_194_304);
     }
 }
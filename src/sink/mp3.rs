@@ -0,0 +1,323 @@
+/// MP3 output, for players that don't take anything else. This builds LAME from source, so it needs
+/// a C compiler but no system libraries. Reading it back for `--verify` uses symphonia.
+use super::{AudioSink, BitrateMode, Metadata, SinkOptions};
+use mp3lame_encoder::{
+    Bitrate, Builder, DualPcm, Encoder, FlushGap, Id3Tag, Mode, Quality, max_required_buffer_size,
+};
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    num::NonZeroU32,
+    path::Path,
+};
+use symphonia::core::{
+    audio::SampleBuffer, codecs::DecoderOptions, errors::Error as SymphoniaError,
+    formats::FormatOptions, io::MediaSourceStream, meta::MetadataOptions, probe::Hint,
+};
+
+/// Default bitrate in kbps, which keeps even quiet low beats intact.
+const DEFAULT_BITRATE: u32 = 192;
+/// Frames handed to LAME at a time
+const BLOCK: usize = 4096;
+/// Roughly what each of LAME's VBR presets, -V0 to -V9, averages in kbps. VBR picks the one
+/// closest to the bitrate asked for.
+const VBR_PRESETS: [u32; 10] = [245, 225, 190, 175, 165, 130, 115, 100, 85, 65];
+
+fn cbr_bitrate(kbps: u32) -> Result<Bitrate, Box<dyn Error>> {
+    Ok(match kbps {
+        8 => Bitrate::Kbps8,
+        16 => Bitrate::Kbps16,
+        24 => Bitrate::Kbps24,
+        32 => Bitrate::Kbps32,
+        40 => Bitrate::Kbps40,
+        48 => Bitrate::Kbps48,
+        64 => Bitrate::Kbps64,
+        80 => Bitrate::Kbps80,
+        96 => Bitrate::Kbps96,
+        112 => Bitrate::Kbps112,
+        128 => Bitrate::Kbps128,
+        160 => Bitrate::Kbps160,
+        192 => Bitrate::Kbps192,
+        224 => Bitrate::Kbps224,
+        256 => Bitrate::Kbps256,
+        320 => Bitrate::Kbps320,
+        other => {
+            return Err(format!(
+                "mp3: a constant bitrate of {} kbps isn't supported, use one of 8, 16, 24, 32, 40, 48, 64, 80, 96, 112, 128, 160, 192, 224, 256 or 320",
+                other
+            )
+            .into());
+        }
+    })
+}
+
+/// The VBR preset that averages closest to `kbps`
+fn vbr_quality(kbps: u32) -> Quality {
+    let preset = (0..VBR_PRESETS.len())
+        .min_by_key(|i| VBR_PRESETS[*i].abs_diff(kbps))
+        .unwrap_or(2);
+    match preset {
+        0 => Quality::Best,
+        1 => Quality::SecondBest,
+        2 => Quality::NearBest,
+        3 => Quality::VeryNice,
+        4 => Quality::Nice,
+        5 => Quality::Good,
+        6 => Quality::Decent,
+        7 => Quality::Ok,
+        8 => Quality::SecondWorst,
+        _ => Quality::Worst,
+    }
+}
+
+fn lame_error(what: &str, err: impl std::fmt::Debug) -> Box<dyn Error> {
+    format!("mp3: {} failed: {:?}", what, err).into()
+}
+
+fn id3_tag(meta: &Metadata) -> Id3Tag<'_> {
+    fn bytes(field: &Option<String>) -> &[u8] {
+        field.as_deref().unwrap_or("").as_bytes()
+    }
+    Id3Tag {
+        title: bytes(&meta.title),
+        artist: bytes(&meta.artist),
+        album: bytes(&meta.album),
+        album_art: &[],
+        year: &[],
+        comment: bytes(&meta.comment),
+    }
+}
+
+pub struct Mp3Sink {
+    enc: Encoder,
+    writer: BufWriter<File>,
+    left: Vec<f32>,
+    right: Vec<f32>,
+    /// Encoded bytes waiting to be written
+    mp3: Vec<u8>,
+}
+
+impl Mp3Sink {
+    pub fn create(out: &str, opts: &SinkOptions) -> Result<Box<dyn AudioSink>, Box<dyn Error>> {
+        let sr = opts.sample_rate;
+        let bitrate = opts.bitrate.unwrap_or(DEFAULT_BITRATE);
+        let mut builder = Builder::new().ok_or("mp3: couldn't create a LAME encoder")?;
+        builder
+            .set_sample_rate(sr)
+            .map_err(|e| lame_error("setting the sample rate", e))?;
+        // Without this LAME resamples lower bitrates down, which would move the beats.
+        builder
+            .set_output_sample_rate(NonZeroU32::new(sr))
+            .map_err(|_| {
+                format!(
+                    "mp3: a sample rate of {} isn't supported, use 8000, 11025, 12000, 16000, 22050, 24000, 32000, 44100 or 48000",
+                    sr
+                )
+            })?;
+        builder
+            .set_num_channels(2)
+            .map_err(|e| lame_error("setting the channels", e))?;
+        // Joint stereo can store the two channels as their sum and difference, and the difference
+        // is where the beat lives, so keep them apart.
+        builder
+            .set_mode(Mode::Stereo)
+            .map_err(|e| lame_error("setting stereo", e))?;
+        match opts.bitrate_mode.unwrap_or(BitrateMode::Cbr) {
+            BitrateMode::Cbr => {
+                builder
+                    .set_brate(cbr_bitrate(bitrate)?)
+                    .map_err(|e| lame_error("setting the bitrate", e))?;
+                builder
+                    .set_quality(Quality::NearBest)
+                    .map_err(|e| lame_error("setting the quality", e))?;
+            }
+            BitrateMode::Vbr => {
+                builder
+                    .set_vbr_mode(Default::default())
+                    .map_err(|e| lame_error("setting vbr", e))?;
+                builder
+                    .set_vbr_quality(vbr_quality(bitrate))
+                    .map_err(|e| lame_error("setting the vbr quality", e))?;
+            }
+        }
+        builder
+            .set_id3_tag(id3_tag(&opts.meta))
+            .map_err(|e| lame_error("setting the ID3 tag", e))?;
+        let enc = builder
+            .build()
+            .map_err(|e| lame_error("starting the encoder", e))?;
+
+        Ok(Box::new(Mp3Sink {
+            enc,
+            writer: BufWriter::new(File::create(Path::new(out))?),
+            left: Vec::with_capacity(BLOCK),
+            right: Vec::with_capacity(BLOCK),
+            mp3: Vec::with_capacity(max_required_buffer_size(BLOCK)),
+        }))
+    }
+
+    fn encode_block(&mut self) -> Result<(), Box<dyn Error>> {
+        let pcm = DualPcm {
+            left: &self.left[..],
+            right: &self.right[..],
+        };
+        self.mp3.clear();
+        self.enc.encode_to_vec(pcm, &mut self.mp3)?;
+        self.writer.write_all(&self.mp3)?;
+        self.left.clear();
+        self.right.clear();
+        Ok(())
+    }
+}
+
+impl AudioSink for Mp3Sink {
+    fn write_frame(&mut self, l: f32, r: f32) -> Result<(), Box<dyn Error>> {
+        self.left.push(l);
+        self.right.push(r);
+        if self.left.len() == BLOCK {
+            self.encode_block()?;
+        }
+        Ok(())
+    }
+
+    fn finalize(mut self: Box<Self>) -> Result<(), Box<dyn Error>> {
+        if !self.left.is_empty() {
+            self.encode_block()?;
+        }
+        self.mp3.clear();
+        self.enc.flush_to_vec::<FlushGap>(&mut self.mp3)?;
+        self.writer.write_all(&self.mp3)?;
+        let mut file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        // The first frame is left blank for the LAME tag, which has the length and the encoder
+        // delay, so players know the duration and can skip the padding.
+        if self.enc.is_lame_tag_written() {
+            let mut tag = Vec::with_capacity(self.enc.lame_tag_size());
+            if self.enc.lame_tag_encode_to_vec(&mut tag).is_some() {
+                file.seek(SeekFrom::Start(self.enc.id3v2_tag_size() as u64))?;
+                file.write_all(&tag)?;
+            }
+        }
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// Read an MP3 back as frames, with the encoder delay and padding trimmed off.
+pub fn decode(path: &str, on_frame: &mut dyn FnMut(f32, f32)) -> Result<u32, Box<dyn Error>> {
+    let file = File::open(Path::new(path))?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension("mp3");
+    let format_opts = FormatOptions {
+        enable_gapless: true,
+        ..Default::default()
+    };
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        stream,
+        &format_opts,
+        &MetadataOptions::default(),
+    )?;
+    let mut format = probed.format;
+    let track = format.default_track().ok_or("mp3: no audio in the file")?;
+    let track_id = track.id;
+    let sr = track
+        .codec_params
+        .sample_rate
+        .ok_or("mp3: no sample rate")?;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+    let mut samples: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = decoder.decode(&packet)?;
+        let channels = decoded.spec().channels.count();
+        let buf = samples
+            .get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, *decoded.spec()));
+        buf.copy_interleaved_ref(decoded);
+        for frame in buf.samples().chunks_exact(channels) {
+            on_frame(frame[0], frame[channels.min(2) - 1]);
+        }
+    }
+    Ok(sr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn write_sine(path: &str, opts: &SinkOptions, n: usize) {
+        let mut sink = Mp3Sink::create(path, opts).unwrap();
+        for i in 0..n {
+            let x = 0.5 * (i as f32 * 0.05).sin();
+            sink.write_frame(x, -x).unwrap();
+        }
+        sink.finalize().unwrap();
+    }
+
+    #[test]
+    fn test_mp3_round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("out.mp3");
+        let path = path.to_str().unwrap();
+        let opts = SinkOptions {
+            sample_rate: 44100,
+            meta: Metadata {
+                title: Some("Body Scan".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        write_sine(path, &opts, 44100);
+
+        let bytes = std::fs::read(path).unwrap();
+        assert_eq!(&bytes[..3], b"ID3");
+        assert!(bytes.windows(9).any(|w| w == b"Body Scan"));
+
+        let mut decoded = Vec::new();
+        let sr = decode(path, &mut |l, r| decoded.push((l, r))).unwrap();
+        assert_eq!(sr, 44100);
+        // The LAME tag lets the decoder trim it back to the exact length.
+        assert_eq!(decoded.len(), 44100);
+        let (l, r) = decoded[22050];
+        let x = 0.5 * (22050.0_f32 * 0.05).sin();
+        assert!((l - x).abs() < 0.05, "{} vs {}", l, x);
+        assert!((r + x).abs() < 0.05, "{} vs {}", r, -x);
+    }
+
+    #[test]
+    fn test_mp3_bitrates() {
+        assert!(cbr_bitrate(128).is_ok());
+        assert!(cbr_bitrate(100).is_err());
+        assert!(matches!(vbr_quality(190), Quality::NearBest));
+        assert!(matches!(vbr_quality(20), Quality::Worst));
+
+        let dir = tempdir().unwrap();
+        let cbr = dir.path().join("cbr.mp3");
+        let vbr = dir.path().join("vbr.mp3");
+        let mut opts = SinkOptions {
+            sample_rate: 48000,
+            bitrate: Some(96),
+            ..Default::default()
+        };
+        write_sine(cbr.to_str().unwrap(), &opts, 48000);
+        opts.bitrate_mode = Some(BitrateMode::Vbr);
+        write_sine(vbr.to_str().unwrap(), &opts, 48000);
+        let cbr = std::fs::read(cbr).unwrap();
+        let vbr = std::fs::read(vbr).unwrap();
+        // The LAME tag is marked "Info" for constant bitrate and "Xing" for variable.
+        assert!(cbr.windows(4).any(|w| w == b"Info"));
+        assert!(vbr.windows(4).any(|w| w == b"Xing"));
+    }
+}
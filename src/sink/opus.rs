@@ -0,0 +1,346 @@
+/// Ogg Opus output, for small files to put on phones. The encoder is libopus transpiled to Rust, so
+/// there's nothing to install, but it's still C underneath and only has a raw pointer API. That's
+/// kept to the `Encoder` and `Decoder` wrappers here.
+///
+/// Opus only runs at 8, 12, 16, 24 or 48 kHz. The default sample rate of 48000 is fine.
+use super::{AudioSink, BitrateMode, SinkOptions, vorbis_comment_header};
+use ogg::PacketReader;
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+use unsafe_libopus::{
+    OPUS_APPLICATION_AUDIO, OPUS_GET_LOOKAHEAD_REQUEST, OPUS_OK, OPUS_SET_BITRATE_REQUEST,
+    OPUS_SET_VBR_REQUEST, OpusDecoder, OpusEncoder, opus_decode_float, opus_decoder_create,
+    opus_decoder_destroy, opus_encode_float, opus_encoder_create, opus_encoder_ctl,
+    opus_encoder_destroy, opus_strerror,
+};
+
+/// Default bitrate in kbps, which keeps even quiet low beats intact.
+const DEFAULT_BITRATE: u32 = 96;
+const SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+/// Ogg Opus granule positions always count samples at 48 kHz.
+const GRANULE_RATE: u64 = 48000;
+/// The biggest packet libopus will make, per the docs
+const MAX_PACKET: usize = 4000;
+/// The longest frame a packet can hold, 120ms at 48 kHz
+const MAX_FRAME: usize = 5760;
+
+fn opus_error(func: &str, code: i32) -> Box<dyn Error> {
+    format!("opus: {} failed: {}", func, opus_strerror(code)).into()
+}
+
+struct Encoder(*mut OpusEncoder);
+
+impl Encoder {
+    fn new(sample_rate: u32, bitrate: u32, vbr: bool) -> Result<Self, Box<dyn Error>> {
+        let mut err = 0;
+        // SAFETY: the pointer is only used through this wrapper, and freed once on drop.
+        let enc =
+            unsafe { opus_encoder_create(sample_rate as i32, 2, OPUS_APPLICATION_AUDIO, &mut err) };
+        if err != OPUS_OK || enc.is_null() {
+            return Err(opus_error("opus_encoder_create", err));
+        }
+        let enc = Encoder(enc);
+        let ret = unsafe { opus_encoder_ctl!(enc.0, OPUS_SET_BITRATE_REQUEST, bitrate as i32) };
+        if ret != OPUS_OK {
+            return Err(opus_error("setting the bitrate", ret));
+        }
+        let ret = unsafe { opus_encoder_ctl!(enc.0, OPUS_SET_VBR_REQUEST, vbr as i32) };
+        if ret != OPUS_OK {
+            return Err(opus_error("setting the bitrate mode", ret));
+        }
+        Ok(enc)
+    }
+
+    /// How many samples the encoder delays its input by.
+    fn lookahead(&self) -> Result<u32, Box<dyn Error>> {
+        let mut lookahead = 0_i32;
+        let ret = unsafe { opus_encoder_ctl!(self.0, OPUS_GET_LOOKAHEAD_REQUEST, &mut lookahead) };
+        if ret != OPUS_OK {
+            return Err(opus_error("getting the lookahead", ret));
+        }
+        Ok(lookahead as u32)
+    }
+
+    /// Encode one frame of interleaved stereo into a packet.
+    fn encode(&mut self, pcm: &[f32]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut packet = vec![0u8; MAX_PACKET];
+        let len = unsafe {
+            opus_encode_float(
+                self.0,
+                pcm.as_ptr(),
+                (pcm.len() / 2) as i32,
+                packet.as_mut_ptr(),
+                MAX_PACKET as i32,
+            )
+        };
+        if len < 0 {
+            return Err(opus_error("opus_encode_float", len));
+        }
+        packet.truncate(len as usize);
+        Ok(packet)
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        unsafe { opus_encoder_destroy(self.0) }
+    }
+}
+
+struct Decoder(*mut OpusDecoder);
+
+impl Decoder {
+    fn new(sample_rate: u32) -> Result<Self, Box<dyn Error>> {
+        let mut err = 0;
+        let dec = unsafe { opus_decoder_create(sample_rate as i32, 2, &mut err) };
+        if err != OPUS_OK || dec.is_null() {
+            return Err(opus_error("opus_decoder_create", err));
+        }
+        Ok(Decoder(dec))
+    }
+
+    /// Decode a packet into interleaved stereo, returning how many frames it held.
+    fn decode(&mut self, packet: &[u8], pcm: &mut [f32]) -> Result<usize, Box<dyn Error>> {
+        let frames = unsafe {
+            opus_decode_float(
+                self.0,
+                packet.as_ptr(),
+                packet.len() as i32,
+                pcm.as_mut_ptr(),
+                (pcm.len() / 2) as i32,
+                0,
+            )
+        };
+        if frames < 0 {
+            return Err(opus_error("opus_decode_float", frames));
+        }
+        Ok(frames as usize)
+    }
+}
+
+impl Drop for Decoder {
+    fn drop(&mut self) {
+        unsafe { opus_decoder_destroy(self.0) }
+    }
+}
+
+/// The identification header, see RFC 7845 section 5.1
+fn opus_head(pre_skip: u16, sample_rate: u32) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1); // version
+    head.push(2); // channels
+    head.extend_from_slice(&pre_skip.to_le_bytes());
+    head.extend_from_slice(&sample_rate.to_le_bytes());
+    head.extend_from_slice(&0_i16.to_le_bytes()); // output gain
+    head.push(0); // channel mapping family: mono or stereo
+    head
+}
+
+/// The comment header, with the tags and chapters
+fn opus_tags(opts: &SinkOptions) -> Vec<u8> {
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend_from_slice(&vorbis_comment_header(opts));
+    tags
+}
+
+pub struct OpusSink {
+    writer: PacketWriter<'static, BufWriter<File>>,
+    enc: Encoder,
+    serial: u32,
+    /// Samples per channel in each packet, 20ms
+    frame_len: usize,
+    /// Interleaved samples waiting for a full frame
+    buf: Vec<f32>,
+    /// The last packet is held back, since it has to be written as the end of the stream.
+    pending: Option<Vec<u8>>,
+    /// Frames written by the render
+    frames_in: u64,
+    /// Packets encoded so far
+    packets: u64,
+    /// Encoder delay at the input rate, and in granule units
+    lookahead: u32,
+    pre_skip: u64,
+    /// 48000 / sample rate
+    granule_scale: u64,
+}
+
+impl OpusSink {
+    pub fn create(out: &str, opts: &SinkOptions) -> Result<Box<dyn AudioSink>, Box<dyn Error>> {
+        let sr = opts.sample_rate;
+        if !SAMPLE_RATES.contains(&sr) {
+            return Err(format!(
+                "opus: a sample rate of {} isn't supported, use 8000, 12000, 16000, 24000 or 48000, eg: `sample_rate: 48000`",
+                sr
+            )
+            .into());
+        }
+        let bitrate = opts.bitrate.unwrap_or(DEFAULT_BITRATE);
+        let vbr = opts.bitrate_mode != Some(BitrateMode::Cbr);
+        let enc = Encoder::new(sr, bitrate * 1000, vbr)?;
+        let lookahead = enc.lookahead()?;
+        let granule_scale = GRANULE_RATE / sr as u64;
+        let pre_skip = lookahead as u64 * granule_scale;
+
+        let file = BufWriter::new(File::create(Path::new(out))?);
+        let mut writer = PacketWriter::new(file);
+        let serial: u32 = rand::random();
+        // Each header goes on a page of its own.
+        writer.write_packet(
+            opus_head(pre_skip as u16, sr),
+            serial,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )?;
+        writer.write_packet(opus_tags(opts), serial, PacketWriteEndInfo::EndPage, 0)?;
+
+        let frame_len = sr as usize / 50;
+        Ok(Box::new(OpusSink {
+            writer,
+            enc,
+            serial,
+            frame_len,
+            buf: Vec::with_capacity(frame_len * 2),
+            pending: None,
+            frames_in: 0,
+            packets: 0,
+            lookahead,
+            pre_skip,
+            granule_scale,
+        }))
+    }
+
+    fn encode_frame(&mut self) -> Result<(), Box<dyn Error>> {
+        let packet = self.enc.encode(&self.buf)?;
+        self.buf.clear();
+        if let Some(prev) = self.pending.replace(packet) {
+            // A packet's granule is where the decoded audio is up to once it's played.
+            let granule = self.packets * self.frame_len as u64 * self.granule_scale;
+            self.writer.write_packet(
+                prev,
+                self.serial,
+                PacketWriteEndInfo::NormalPacket,
+                granule,
+            )?;
+        }
+        self.packets += 1;
+        Ok(())
+    }
+}
+
+impl AudioSink for OpusSink {
+    fn write_frame(&mut self, l: f32, r: f32) -> Result<(), Box<dyn Error>> {
+        self.buf.push(l);
+        self.buf.push(r);
+        self.frames_in += 1;
+        if self.buf.len() == self.frame_len * 2 {
+            self.encode_frame()?;
+        }
+        Ok(())
+    }
+
+    fn finalize(mut self: Box<Self>) -> Result<(), Box<dyn Error>> {
+        // Feed in silence to flush the encoder's lookahead, in whole frames.
+        let mut flush = self.lookahead as usize;
+        while flush > 0 || !self.buf.is_empty() {
+            self.buf.push(0.0);
+            self.buf.push(0.0);
+            flush = flush.saturating_sub(1);
+            if self.buf.len() == self.frame_len * 2 {
+                self.encode_frame()?;
+            }
+        }
+        // The last granule says where the audio really ends, so players drop the padding.
+        let granule = self.pre_skip + self.frames_in * self.granule_scale;
+        if let Some(last) = self.pending.take() {
+            self.writer
+                .write_packet(last, self.serial, PacketWriteEndInfo::EndStream, granule)?;
+        }
+        let mut file = self.writer.into_inner();
+        std::io::Write::flush(&mut file)?;
+        Ok(())
+    }
+}
+
+/// Read an Ogg Opus file back as frames at the rate it was encoded from.
+pub fn decode(path: &str, on_frame: &mut dyn FnMut(f32, f32)) -> Result<u32, Box<dyn Error>> {
+    let mut reader = PacketReader::new(BufReader::new(File::open(Path::new(path))?));
+    let head = reader.read_packet()?.ok_or("opus: the file is empty")?.data;
+    if head.len() < 19 || &head[..8] != b"OpusHead" {
+        return Err("opus: the file doesn't start with an OpusHead".into());
+    }
+    let pre_skip = u16::from_le_bytes([head[10], head[11]]) as u64;
+    let input_rate = u32::from_le_bytes([head[12], head[13], head[14], head[15]]);
+    let sr = if SAMPLE_RATES.contains(&input_rate) {
+        input_rate
+    } else {
+        GRANULE_RATE as u32
+    };
+    // The comment header
+    reader.read_packet()?;
+
+    let mut dec = Decoder::new(sr)?;
+    let mut skip = (pre_skip * sr as u64 / GRANULE_RATE) as usize;
+    let mut pcm = vec![0.0_f32; MAX_FRAME * 2];
+    while let Some(packet) = reader.read_packet()? {
+        let frames = dec.decode(&packet.data, &mut pcm)?;
+        let start = skip.min(frames);
+        skip -= start;
+        for frame in pcm[start * 2..frames * 2].chunks_exact(2) {
+            on_frame(frame[0], frame[1]);
+        }
+    }
+    Ok(sr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_opus_round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("out.opus");
+        let path = path.to_str().unwrap();
+        let opts = SinkOptions {
+            sample_rate: 48000,
+            ..Default::default()
+        };
+        let mut sink = OpusSink::create(path, &opts).unwrap();
+        let n = 48000;
+        for i in 0..n {
+            let x = 0.5 * (i as f32 * 0.05).sin();
+            sink.write_frame(x, -x).unwrap();
+        }
+        sink.finalize().unwrap();
+
+        let mut decoded = Vec::new();
+        let sr = decode(path, &mut |l, r| decoded.push((l, r))).unwrap();
+        assert_eq!(sr, 48000);
+        // The pre-skip lines it back up, give or take the padding of the last frame.
+        assert!(decoded.len() >= n && decoded.len() < n + 2 * 960);
+        let (l, r) = decoded[24000];
+        let x = 0.5 * (24000.0_f32 * 0.05).sin();
+        assert!((l - x).abs() < 0.05, "{} vs {}", l, x);
+        assert!((r + x).abs() < 0.05, "{} vs {}", r, -x);
+    }
+
+    #[test]
+    fn test_opus_sample_rate() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("out.opus");
+        let opts = SinkOptions {
+            sample_rate: 44100,
+            ..Default::default()
+        };
+        let err = OpusSink::create(path.to_str().unwrap(), &opts)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("48000"));
+    }
+}
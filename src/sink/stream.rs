@@ -0,0 +1,160 @@
+/// Output to a writer that can't seek, eg: stdout piped into ffmpeg or sox. A WAV header has the
+/// length at the start, which isn't known until the end, so this writes the largest possible size
+/// instead, like ffmpeg and sox do when they stream WAV. Both read that as "until the end of the
+/// stream". Raw output has no header at all, and whatever reads it has to be told the format.
+use super::{AudioSink, BitDepth, Quantizer, SinkOptions, wav};
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+/// The RIFF and data chunk sizes when the length isn't known
+const UNKNOWN_SIZE: u32 = u32::MAX;
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+
+pub struct StreamSink<W: Write> {
+    writer: W,
+    bit_depth: BitDepth,
+    quantizer: Quantizer,
+}
+
+/// A WAV header for stereo audio of unknown length. The tags and chapters go before the audio,
+/// since there's no coming back to add them after.
+fn wav_header(opts: &SinkOptions) -> Vec<u8> {
+    let bits = opts.bit_depth.bits();
+    let block_align = 2 * bits / 8;
+    let format = match opts.bit_depth {
+        BitDepth::Float32 => WAVE_FORMAT_IEEE_FLOAT,
+        BitDepth::Int16 | BitDepth::Int24 => WAVE_FORMAT_PCM,
+    };
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&UNKNOWN_SIZE.to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16_u32.to_le_bytes());
+    header.extend_from_slice(&format.to_le_bytes());
+    header.extend_from_slice(&2_u16.to_le_bytes());
+    header.extend_from_slice(&opts.sample_rate.to_le_bytes());
+    header.extend_from_slice(&(opts.sample_rate * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&bits.to_le_bytes());
+    header.extend_from_slice(&wav::riff_chunks(opts));
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&UNKNOWN_SIZE.to_le_bytes());
+    header
+}
+
+impl<W: Write> StreamSink<W> {
+    /// Start the stream, with a WAV header unless `raw`.
+    pub fn new(mut writer: W, opts: &SinkOptions, raw: bool) -> Result<Self, Box<dyn Error>> {
+        if !raw {
+            writer.write_all(&wav_header(opts))?;
+        }
+        Ok(StreamSink {
+            writer,
+            bit_depth: opts.bit_depth,
+            quantizer: Quantizer::new(opts),
+        })
+    }
+
+    fn write_sample(&mut self, x: f32, channel: usize) -> io::Result<()> {
+        match self.bit_depth {
+            BitDepth::Int16 => {
+                let s = self.quantizer.quantize(x, channel) as i16;
+                self.writer.write_all(&s.to_le_bytes())
+            }
+            BitDepth::Int24 => {
+                let s = self.quantizer.quantize(x, channel);
+                self.writer.write_all(&s.to_le_bytes()[..3])
+            }
+            BitDepth::Float32 => self.writer.write_all(&x.to_le_bytes()),
+        }
+    }
+}
+
+impl StreamSink<BufWriter<io::StdoutLock<'static>>> {
+    pub fn stdout(opts: &SinkOptions, raw: bool) -> Result<Box<dyn AudioSink>, Box<dyn Error>> {
+        let writer = BufWriter::new(io::stdout().lock());
+        Ok(Box::new(StreamSink::new(writer, opts, raw)?))
+    }
+}
+
+impl StreamSink<BufWriter<File>> {
+    /// Raw samples to a file, which can seek but has nowhere to say what they are.
+    pub fn create_raw(out: &str, opts: &SinkOptions) -> Result<Box<dyn AudioSink>, Box<dyn Error>> {
+        let writer = BufWriter::new(File::create(Path::new(out))?);
+        Ok(Box::new(StreamSink::new(writer, opts, true)?))
+    }
+}
+
+impl<W: Write> AudioSink for StreamSink<W> {
+    fn write_frame(&mut self, l: f32, r: f32) -> Result<(), Box<dyn Error>> {
+        self.write_sample(l, 0)?;
+        self.write_sample(r, 1)?;
+        Ok(())
+    }
+
+    fn finalize(mut self: Box<Self>) -> Result<(), Box<dyn Error>> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(opts: &SinkOptions, raw: bool, frames: &[(f32, f32)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut sink = StreamSink::new(&mut out, opts, raw).unwrap();
+        for (l, r) in frames {
+            sink.write_frame(*l, *r).unwrap();
+        }
+        Box::new(sink).finalize().unwrap();
+        out
+    }
+
+    #[test]
+    fn test_streamed_wav_header() {
+        let opts = SinkOptions {
+            sample_rate: 48000,
+            ..Default::default()
+        };
+        let out = stream(&opts, false, &[(0.5, -0.5)]);
+        assert_eq!(out.len(), 44 + 4);
+        assert_eq!(&out[..4], b"RIFF");
+        assert_eq!(&out[4..8], &[0xff; 4]);
+        assert_eq!(&out[8..16], b"WAVEfmt ");
+        assert_eq!(u32::from_le_bytes(out[24..28].try_into().unwrap()), 48000);
+        // Bytes per second, then bytes per frame and bits per sample
+        assert_eq!(
+            u32::from_le_bytes(out[28..32].try_into().unwrap()),
+            48000 * 4
+        );
+        assert_eq!(&out[32..36], &[4, 0, 16, 0]);
+        assert_eq!(&out[36..44], b"data\xff\xff\xff\xff");
+        assert_eq!(i16::from_le_bytes([out[44], out[45]]), 16384);
+        assert_eq!(i16::from_le_bytes([out[46], out[47]]), -16384);
+    }
+
+    #[test]
+    fn test_raw_samples() {
+        let mut opts = SinkOptions {
+            sample_rate: 44100,
+            bit_depth: BitDepth::Float32,
+            ..Default::default()
+        };
+        let out = stream(&opts, true, &[(0.25, -1.5)]);
+        assert_eq!(out.len(), 8);
+        assert_eq!(f32::from_le_bytes(out[..4].try_into().unwrap()), 0.25);
+        // Float is left unclamped, like in a WAV.
+        assert_eq!(f32::from_le_bytes(out[4..].try_into().unwrap()), -1.5);
+
+        opts.bit_depth = BitDepth::Int24;
+        let out = stream(&opts, true, &[(1.0, -1.0)]);
+        assert_eq!(out, vec![0xff, 0xff, 0x7f, 0x01, 0x00, 0x80]);
+    }
+}
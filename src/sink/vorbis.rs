@@ -0,0 +1,122 @@
+/// Ogg Vorbis output. This builds libvorbis from source with the `cc` crate, so it needs a C
+/// compiler but no system libraries.
+use super::{AudioSink, BitrateMode, SinkOptions, vorbis_comments};
+use log::warn;
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufReader, BufWriter},
+    num::{NonZeroU8, NonZeroU32},
+    path::Path,
+};
+use vorbis_rs::{
+    VorbisBitrateManagementStrategy, VorbisDecoder, VorbisEncoder, VorbisEncoderBuilder,
+};
+
+/// Default bitrate in kbps
+const DEFAULT_BITRATE: u32 = 128;
+/// Frames handed to the encoder at a time, as libvorbis suggests
+const BLOCK: usize = 1024;
+
+pub struct VorbisSink {
+    enc: VorbisEncoder<BufWriter<File>>,
+    left: Vec<f32>,
+    right: Vec<f32>,
+}
+
+impl VorbisSink {
+    pub fn create(out: &str, opts: &SinkOptions) -> Result<Box<dyn AudioSink>, Box<dyn Error>> {
+        let sample_rate =
+            NonZeroU32::new(opts.sample_rate).ok_or("vorbis: the sample rate can't be 0")?;
+        let target_bitrate = NonZeroU32::new(opts.bitrate.unwrap_or(DEFAULT_BITRATE) * 1000)
+            .ok_or("vorbis: the bitrate can't be 0")?;
+        if opts.bitrate_mode == Some(BitrateMode::Cbr) {
+            warn!("Vorbis is always variable bitrate, ignoring bitrate_mode cbr.");
+        }
+        let file = BufWriter::new(File::create(Path::new(out))?);
+        let enc = VorbisEncoderBuilder::new(sample_rate, NonZeroU8::new(2).unwrap(), file)?
+            .comment_tags(vorbis_comments(opts))
+            .map_err(|e| format!("vorbis: can't add the tags: {}", e))?
+            .bitrate_management_strategy(VorbisBitrateManagementStrategy::Vbr { target_bitrate })
+            .build()
+            .map_err(|e| format!("vorbis: can't encode at {} bps: {}", target_bitrate, e))?;
+        Ok(Box::new(VorbisSink {
+            enc,
+            left: Vec::with_capacity(BLOCK),
+            right: Vec::with_capacity(BLOCK),
+        }))
+    }
+
+    fn encode_block(&mut self) -> Result<(), Box<dyn Error>> {
+        self.enc.encode_audio_block([&self.left, &self.right])?;
+        self.left.clear();
+        self.right.clear();
+        Ok(())
+    }
+}
+
+impl AudioSink for VorbisSink {
+    fn write_frame(&mut self, l: f32, r: f32) -> Result<(), Box<dyn Error>> {
+        self.left.push(l);
+        self.right.push(r);
+        if self.left.len() == BLOCK {
+            self.encode_block()?;
+        }
+        Ok(())
+    }
+
+    fn finalize(mut self: Box<Self>) -> Result<(), Box<dyn Error>> {
+        if !self.left.is_empty() {
+            self.encode_block()?;
+        }
+        let mut file = self.enc.finish()?;
+        std::io::Write::flush(&mut file)?;
+        Ok(())
+    }
+}
+
+/// Read an Ogg Vorbis file back as frames.
+pub fn decode(path: &str, on_frame: &mut dyn FnMut(f32, f32)) -> Result<u32, Box<dyn Error>> {
+    let mut dec = VorbisDecoder::new(BufReader::new(File::open(Path::new(path))?))?;
+    let sr = dec.sampling_frequency().get();
+    while let Some(block) = dec.decode_audio_block()? {
+        let channels = block.samples();
+        let (left, right) = (channels[0], channels[channels.len().min(2) - 1]);
+        for (l, r) in left.iter().zip(right.iter()) {
+            on_frame(*l, *r);
+        }
+    }
+    Ok(sr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_vorbis_round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("out.ogg");
+        let path = path.to_str().unwrap();
+        let opts = SinkOptions {
+            sample_rate: 44100,
+            ..Default::default()
+        };
+        let mut sink = VorbisSink::create(path, &opts).unwrap();
+        for i in 0..44100 {
+            let x = 0.5 * (i as f32 * 0.05).sin();
+            sink.write_frame(x, -x).unwrap();
+        }
+        sink.finalize().unwrap();
+
+        let mut decoded = Vec::new();
+        let sr = decode(path, &mut |l, r| decoded.push((l, r))).unwrap();
+        assert_eq!(sr, 44100);
+        assert_eq!(decoded.len(), 44100);
+        let (l, r) = decoded[22050];
+        let x = 0.5 * (22050.0_f32 * 0.05).sin();
+        assert!((l - x).abs() < 0.05, "{} vs {}", l, x);
+        assert!((r + x).abs() < 0.05, "{} vs {}", r, -x);
+    }
+}
@@ -1,35 +1,231 @@
 /// Default audio file writer.
-use super::{AudioSink, f32_to_i16};
+use super::{AudioSink, BitDepth, Quantizer, SinkOptions};
 use hound::{SampleFormat, WavSpec, WavWriter};
-use std::{error::Error, fs::File, io::BufWriter, path::Path};
+use std::{
+    error::Error,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
 
 pub struct WavSink {
     writer: WavWriter<BufWriter<File>>,
+    path: String,
+    /// Tags and chapters to add once hound has written the audio
+    chunks: Vec<u8>,
+    bit_depth: BitDepth,
+    quantizer: Quantizer,
+}
+
+/// Append a RIFF chunk, padded to an even length.
+fn push_chunk(out: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(body);
+    if body.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// A string as RIFF stores it, with a terminating zero
+fn zstr(s: &str) -> Vec<u8> {
+    let mut bytes = s.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+/// The tags as a LIST INFO chunk, and the chapters as cue points named in a LIST adtl chunk, which
+/// is where players and editors like Audacity and Reaper look for markers. Empty if there's
+/// nothing to write.
+pub fn riff_chunks(opts: &SinkOptions) -> Vec<u8> {
+    let mut out = Vec::new();
+    if !opts.chapters.is_empty() {
+        let mut cues = (opts.chapters.len() as u32).to_le_bytes().to_vec();
+        let mut labels = b"adtl".to_vec();
+        for (i, chapter) in opts.chapters.iter().enumerate() {
+            let id = (i as u32 + 1).to_le_bytes();
+            let start = (chapter.start as u32).to_le_bytes();
+            cues.extend_from_slice(&id);
+            cues.extend_from_slice(&start); // position in the playlist, which is just the file
+            cues.extend_from_slice(b"data");
+            cues.extend_from_slice(&[0; 8]); // chunk and block start, both 0 for uncompressed
+            cues.extend_from_slice(&start);
+            let mut label = id.to_vec();
+            label.extend_from_slice(&zstr(&chapter.name));
+            push_chunk(&mut labels, b"labl", &label);
+        }
+        push_chunk(&mut out, b"cue ", &cues);
+        push_chunk(&mut out, b"LIST", &labels);
+    }
+    let meta = &opts.meta;
+    if !meta.is_empty() {
+        let mut info = b"INFO".to_vec();
+        for (id, value) in [
+            (b"INAM", &meta.title),
+            (b"IART", &meta.artist),
+            (b"IPRD", &meta.album),
+            (b"ICMT", &meta.comment),
+            (b"ICOP", &meta.license),
+        ] {
+            if let Some(value) = value {
+                push_chunk(&mut info, id, &zstr(value));
+            }
+        }
+        push_chunk(&mut out, b"LIST", &info);
+    }
+    out
 }
 
 impl WavSink {
-    pub fn create(out: &str, sample_rate: u32) -> Result<Box<dyn AudioSink>, Box<dyn Error>> {
+    pub fn create(out: &str, opts: &SinkOptions) -> Result<Box<dyn AudioSink>, Box<dyn Error>> {
         let spec = WavSpec {
             channels: 2,
-            sample_rate,
-            bits_per_sample: 16,
-            sample_format: SampleFormat::Int,
+            sample_rate: opts.sample_rate,
+            bits_per_sample: opts.bit_depth.bits(),
+            sample_format: match opts.bit_depth {
+                BitDepth::Float32 => SampleFormat::Float,
+                BitDepth::Int16 | BitDepth::Int24 => SampleFormat::Int,
+            },
         };
         let file = std::fs::File::create(Path::new(out))?;
         let buf = BufWriter::new(file);
         let writer = WavWriter::new(buf, spec)?;
-        Ok(Box::new(WavSink { writer }))
+        Ok(Box::new(WavSink {
+            writer,
+            path: out.to_string(),
+            chunks: riff_chunks(opts),
+            bit_depth: opts.bit_depth,
+            quantizer: Quantizer::new(opts),
+        }))
     }
 }
 
 impl AudioSink for WavSink {
     fn write_frame(&mut self, l: f32, r: f32) -> Result<(), Box<dyn Error>> {
-        self.writer.write_sample(f32_to_i16(l))?;
-        self.writer.write_sample(f32_to_i16(r))?;
+        match self.bit_depth {
+            BitDepth::Int16 => {
+                self.writer
+                    .write_sample(self.quantizer.quantize(l, 0) as i16)?;
+                self.writer
+                    .write_sample(self.quantizer.quantize(r, 1) as i16)?;
+            }
+            BitDepth::Int24 => {
+                self.writer.write_sample(self.quantizer.quantize(l, 0))?;
+                self.writer.write_sample(self.quantizer.quantize(r, 1))?;
+            }
+            // Left as it is, so a master keeps anything over full scale for editing later.
+            BitDepth::Float32 => {
+                self.writer.write_sample(l)?;
+                self.writer.write_sample(r)?;
+            }
+        }
         Ok(())
     }
     fn finalize(self: Box<Self>) -> Result<(), Box<dyn Error>> {
         self.writer.finalize()?;
+        if self.chunks.is_empty() {
+            return Ok(());
+        }
+        // hound has no way to write other chunks, so add them after the audio and fix up the
+        // RIFF size to include them.
+        let mut file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        let mut len = file.seek(SeekFrom::End(0))?;
+        if len % 2 == 1 {
+            file.write_all(&[0])?;
+            len += 1;
+        }
+        file.write_all(&self.chunks)?;
+        let riff_size = len + self.chunks.len() as u64 - 8;
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(&(riff_size as u32).to_le_bytes())?;
         Ok(())
     }
 }
+
+/// Read a WAV back as f32 frames in [-1.0, 1.0], whatever its sample format.
+pub fn decode(path: &str, on_frame: &mut dyn FnMut(f32, f32)) -> Result<u32, Box<dyn Error>> {
+    let reader = hound::WavReader::open(Path::new(path))?;
+    let spec = reader.spec();
+    let channels = spec.channels as usize;
+    let mut frame = vec![0.0_f32; channels];
+    let mut i = 0;
+    let mut push = |x: f32| {
+        frame[i] = x;
+        i += 1;
+        if i == channels {
+            on_frame(frame[0], frame[channels.min(2) - 1]);
+            i = 0;
+        }
+    };
+    match spec.sample_format {
+        SampleFormat::Float => {
+            for s in reader.into_samples::<f32>() {
+                push(s?);
+            }
+        }
+        SampleFormat::Int => {
+            let scale = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+            for s in reader.into_samples::<i32>() {
+                push(s? as f32 / scale);
+            }
+        }
+    }
+    Ok(spec.sample_rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{Chapter, Metadata};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_wav_tags_and_cues() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("out.wav");
+        let path = path.to_str().unwrap();
+        let opts = SinkOptions {
+            sample_rate: 8000,
+            meta: Metadata {
+                title: Some("Body Scan".to_string()),
+                license: Some("CC0".to_string()),
+                ..Default::default()
+            },
+            chapters: vec![
+                Chapter {
+                    start: 0,
+                    name: "Start".to_string(),
+                },
+                Chapter {
+                    start: 500,
+                    name: "Return".to_string(),
+                },
+            ],
+            ..Default::default()
+        };
+        let mut sink = WavSink::create(path, &opts).unwrap();
+        for _ in 0..1000 {
+            sink.write_frame(0.25, -0.25).unwrap();
+        }
+        sink.finalize().unwrap();
+
+        let bytes = std::fs::read(path).unwrap();
+        let riff_size = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        assert_eq!(riff_size as usize, bytes.len() - 8);
+        let find = |needle: &[u8]| bytes.windows(needle.len()).position(|w| w == needle);
+        let cue = find(b"cue ").unwrap();
+        // The second cue point is at frame 500, both as its position and its sample offset.
+        let point = &bytes[cue + 12 + 24..cue + 12 + 48];
+        assert_eq!(u32::from_le_bytes(point[..4].try_into().unwrap()), 2);
+        assert_eq!(u32::from_le_bytes(point[4..8].try_into().unwrap()), 500);
+        assert_eq!(u32::from_le_bytes(point[20..].try_into().unwrap()), 500);
+        assert!(find(b"labl\x0b\x00\x00\x00\x02\x00\x00\x00Return\x00").is_some());
+        assert!(find(b"INAM\x0a\x00\x00\x00Body Scan\x00").is_some());
+        assert!(find(b"ICOP\x04\x00\x00\x00CC0\x00").is_some());
+
+        // The extra chunks don't get in the way of reading the audio back.
+        let mut frames = 0;
+        assert_eq!(decode(path, &mut |_, _| frames += 1).unwrap(), 8000);
+        assert_eq!(frames, 1000);
+    }
+}
@@ -0,0 +1,400 @@
+/// Post-processing for generated speech, so narration sits consistently in the mix: trimming the
+/// silence around it, then a high-pass to take out rumble, gentle compression, a small algorithmic
+/// reverb and loudness normalization to a target LUFS, in that order.
+use crate::config::{CompressSpec, ReverbSpec, SpeechFxSpec, TrimSpec};
+use crate::duck::db_to_gain;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use log::debug;
+use std::f32::consts::PI;
+use std::path::Path;
+
+/// Loudness below this is silence, and doesn't count towards the integrated loudness.
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+/// Blocks this far below the ungated loudness don't count either.
+const RELATIVE_GATE_LU: f64 = -10.0;
+/// The reverb tail is cut once it's this quiet.
+const TAIL_FLOOR: f32 = 0.0005;
+
+/// A second order IIR filter, in direct form I.
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl Biquad {
+    fn new(b: [f32; 3], a: [f32; 3]) -> Self {
+        Biquad {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    fn highpass(sample_rate: u32, hz: f32, q: f32) -> Self {
+        let w0 = 2.0 * PI * hz / sample_rate as f32;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
+        Biquad::new(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    fn high_shelf(sample_rate: u32, hz: f32, q: f32, gain_db: f32) -> Self {
+        let a = 10f32.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * hz / sample_rate as f32;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
+        let sqrt_a = a.sqrt();
+        Biquad::new(
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos + 2.0 * sqrt_a * alpha),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - 2.0 * sqrt_a * alpha),
+            ],
+            [
+                (a + 1.0) - (a - 1.0) * cos + 2.0 * sqrt_a * alpha,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - 2.0 * sqrt_a * alpha,
+            ],
+        )
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2
+            - self.a1 * self.y1
+            - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+/// Trim a WAV clip and run the chain over it, and write the result to `out` as 32-bit float mono.
+/// It's written under another name first, so an interrupted run never leaves half a clip to be
+/// used as cached.
+pub fn process_file(
+    src: &Path,
+    out: &Path,
+    trim: Option<&TrimSpec>,
+    spec: Option<&SpeechFxSpec>,
+) -> Result<(), hound::Error> {
+    let (mut samples, sample_rate) = read_mono(src)?;
+    let secs = |n: usize| n as f32 / sample_rate as f32;
+    if let Some(trim) = trim {
+        let (start, end) = speech_bounds(&samples, sample_rate, trim);
+        debug!(
+            "trimmed {:.2}s of leading and {:.2}s of trailing silence from {:?}, leaving {:.2}s",
+            secs(start),
+            secs(samples.len() - end),
+            src,
+            secs(end - start)
+        );
+        samples.truncate(end);
+        samples.drain(..start);
+    }
+    let processed = match spec {
+        Some(spec) => process(&samples, sample_rate, spec),
+        None => samples,
+    };
+    debug!("processed clip {:?} is {:.2}s", out, secs(processed.len()));
+    let mut partial = out.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = Path::new(&partial);
+    let wav_spec = WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let mut writer = WavWriter::create(partial, wav_spec)?;
+    for s in processed {
+        writer.write_sample(s)?;
+    }
+    writer.finalize()?;
+    std::fs::rename(partial, out)?;
+    Ok(())
+}
+
+/// A WAV file's samples as f32, with the channels averaged.
+fn read_mono(path: &Path) -> Result<(Vec<f32>, u32), hound::Error> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let channels = spec.channels.max(1) as usize;
+    let interleaved: Vec<f32> = match spec.sample_format {
+        SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        SampleFormat::Int => {
+            let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 * scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    let samples = interleaved
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+    Ok((samples, spec.sample_rate))
+}
+
+/// Where the speech starts and ends, as a range of samples with the silence either side left out
+/// except for the padding. A clip that's all silence is kept as it is.
+pub fn speech_bounds(samples: &[f32], sample_rate: u32, spec: &TrimSpec) -> (usize, usize) {
+    let threshold = db_to_gain(spec.threshold_db);
+    let loud = |s: &f32| s.abs() > threshold;
+    let (Some(first), Some(last)) = (
+        samples.iter().position(loud),
+        samples.iter().rposition(loud),
+    ) else {
+        return (0, samples.len());
+    };
+    let pad = (spec.pad_ms.max(0.0) * 0.001 * sample_rate as f32) as usize;
+    (
+        first.saturating_sub(pad),
+        (last + 1 + pad).min(samples.len()),
+    )
+}
+
+/// Run the chain over a mono clip. The reverb can make it longer.
+pub fn process(samples: &[f32], sample_rate: u32, spec: &SpeechFxSpec) -> Vec<f32> {
+    let mut out = samples.to_vec();
+    if let Some(hz) = spec.highpass {
+        highpass(&mut out, sample_rate, hz);
+    }
+    if let Some(compress) = &spec.compress {
+        compressor(&mut out, sample_rate, compress);
+    }
+    if let Some(reverb_spec) = &spec.reverb {
+        out = reverb(&out, sample_rate, reverb_spec);
+    }
+    if let Some(target) = spec.loudness {
+        normalize(&mut out, sample_rate, target);
+    }
+    out
+}
+
+/// Butterworth high-pass, to take out rumble and plosive thumps below `hz`.
+pub fn highpass(samples: &mut [f32], sample_rate: u32, hz: f32) {
+    let mut filter = Biquad::highpass(sample_rate, hz, std::f32::consts::FRAC_1_SQRT_2);
+    for s in samples.iter_mut() {
+        *s = filter.process(*s);
+    }
+}
+
+/// Feed-forward compressor on the peak envelope, without makeup gain since normalization sets the
+/// level after.
+pub fn compressor(samples: &mut [f32], sample_rate: u32, spec: &CompressSpec) {
+    let coeff = |ms: f32| (-1.0 / (ms.max(0.1) * 0.001 * sample_rate as f32)).exp();
+    let (attack, release) = (coeff(spec.attack_ms), coeff(spec.release_ms));
+    let ratio = spec.ratio.max(1.0);
+    let mut env = 0.0_f32;
+    for s in samples.iter_mut() {
+        let level = s.abs();
+        let c = if level > env { attack } else { release };
+        env = c * env + (1.0 - c) * level;
+        let env_db = 20.0 * env.max(1e-9).log10();
+        let over = env_db - spec.threshold_db;
+        if over > 0.0 {
+            *s *= db_to_gain(-over * (1.0 - 1.0 / ratio));
+        }
+    }
+}
+
+/// A small Schroeder reverb: parallel damped combs into allpasses, mixed in under the dry signal.
+/// The comb and allpass lengths are the usual Freeverb ones, scaled from 44.1kHz.
+pub fn reverb(samples: &[f32], sample_rate: u32, spec: &ReverbSpec) -> Vec<f32> {
+    const COMBS: [usize; 4] = [1116, 1188, 1277, 1356];
+    const ALLPASSES: [usize; 2] = [556, 441];
+    let scale = |len: usize| ((len as f32 * sample_rate as f32 / 44100.0) as usize).max(1);
+    let room = spec.room.clamp(0.0, 1.0);
+    let feedback = 0.7 + 0.28 * room;
+    let damping = spec.damping.clamp(0.0, 1.0);
+    let mix = spec.mix.clamp(0.0, 1.0);
+
+    let mut combs: Vec<(Vec<f32>, usize, f32)> = COMBS
+        .iter()
+        .map(|len| (vec![0.0; scale(*len)], 0, 0.0))
+        .collect();
+    let mut allpasses: Vec<(Vec<f32>, usize)> = ALLPASSES
+        .iter()
+        .map(|len| (vec![0.0; scale(*len)], 0))
+        .collect();
+
+    // Room for the tail to ring out, which is cut where it goes quiet.
+    let tail = ((1.0 + 2.0 * room) * sample_rate as f32) as usize;
+    let mut out: Vec<f32> = Vec::with_capacity(samples.len() + tail);
+    for n in 0..samples.len() + tail {
+        let dry = samples.get(n).copied().unwrap_or(0.0);
+        let mut wet = 0.0;
+        for (buf, pos, filtered) in combs.iter_mut() {
+            let delayed = buf[*pos];
+            *filtered = delayed * (1.0 - damping) + *filtered * damping;
+            buf[*pos] = dry + *filtered * feedback;
+            *pos = (*pos + 1) % buf.len();
+            wet += delayed;
+        }
+        wet /= COMBS.len() as f32;
+        for (buf, pos) in allpasses.iter_mut() {
+            let delayed = buf[*pos];
+            buf[*pos] = wet + delayed * 0.5;
+            *pos = (*pos + 1) % buf.len();
+            wet = delayed - wet;
+        }
+        out.push(dry * (1.0 - mix) + wet * mix);
+    }
+    let end = out
+        .iter()
+        .rposition(|s| s.abs() > TAIL_FLOOR)
+        .map(|i| i + 1)
+        .unwrap_or(0)
+        .max(samples.len());
+    out.truncate(end);
+    out
+}
+
+/// Integrated loudness of a mono clip in LUFS, per ITU-R BS.1770: K-weighted, in 400ms blocks
+/// overlapping by 75%, gated absolutely and then relatively. `None` for silence or clips shorter
+/// than a block.
+pub fn loudness(samples: &[f32], sample_rate: u32) -> Option<f64> {
+    let mut shelf = Biquad::high_shelf(sample_rate, 1500.0, 0.707_175_2, 4.0);
+    let mut hp = Biquad::highpass(sample_rate, 38.0, 0.500_327);
+    let squared: Vec<f64> = samples
+        .iter()
+        .map(|s| (hp.process(shelf.process(*s)) as f64).powi(2))
+        .collect();
+
+    let block = (0.4 * sample_rate as f64) as usize;
+    let step = (block / 4).max(1);
+    if block == 0 || squared.len() < block {
+        return None;
+    }
+    let to_lufs = |mean_square: f64| -0.691 + 10.0 * mean_square.log10();
+    let blocks: Vec<f64> = (0..=(squared.len() - block) / step)
+        .map(|i| squared[i * step..i * step + block].iter().sum::<f64>() / block as f64)
+        .filter(|ms| *ms > 0.0 && to_lufs(*ms) > ABSOLUTE_GATE_LUFS)
+        .collect();
+    if blocks.is_empty() {
+        return None;
+    }
+    let ungated = to_lufs(blocks.iter().sum::<f64>() / blocks.len() as f64);
+    let gated: Vec<f64> = blocks
+        .into_iter()
+        .filter(|ms| to_lufs(*ms) > ungated + RELATIVE_GATE_LU)
+        .collect();
+    Some(to_lufs(gated.iter().sum::<f64>() / gated.len() as f64))
+}
+
+/// Change the gain so the clip's integrated loudness is `target` LUFS, but never so loud that it
+/// clips.
+pub fn normalize(samples: &mut [f32], sample_rate: u32, target: f32) {
+    let Some(measured) = loudness(samples, sample_rate) else {
+        debug!("speech is silent or too short to measure its loudness, leaving it");
+        return;
+    };
+    let mut gain = db_to_gain(target - measured as f32);
+    let peak = samples.iter().fold(0.0_f32, |m, s| m.max(s.abs()));
+    if peak * gain > 1.0 {
+        debug!(
+            "normalizing {:.1} LUFS to {:.1} LUFS would clip, so it's limited by the peak",
+            measured, target
+        );
+        gain = 1.0 / peak;
+    }
+    for s in samples.iter_mut() {
+        *s *= gain;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(hz: f32, amp: f32, secs: f32, sr: u32) -> Vec<f32> {
+        (0..(secs * sr as f32) as usize)
+            .map(|n| amp * (2.0 * PI * hz * n as f32 / sr as f32).sin())
+            .collect()
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn test_speech_bounds() {
+        let spec = TrimSpec::default();
+        let mut samples = vec![0.0; 1000];
+        samples.extend(vec![0.5; 100]);
+        samples.extend(vec![0.001; 2000]);
+        // 20ms of padding is 20 samples at 1kHz.
+        assert_eq!(speech_bounds(&samples, 1000, &spec), (980, 1120));
+        assert_eq!(speech_bounds(&[0.0; 50], 1000, &spec), (0, 50));
+    }
+
+    #[test]
+    fn test_loudness_of_full_scale_sine() {
+        // A full scale 1kHz sine in one channel is -3.01 LUFS.
+        let lufs = loudness(&sine(997.0, 1.0, 2.0, 48000), 48000).unwrap();
+        assert!((lufs + 3.01).abs() < 0.1, "{}", lufs);
+        assert!(loudness(&vec![0.0; 48000], 48000).is_none());
+    }
+
+    #[test]
+    fn test_normalize_to_target() {
+        let mut samples = sine(440.0, 0.05, 2.0, 22050);
+        normalize(&mut samples, 22050, -18.0);
+        let lufs = loudness(&samples, 22050).unwrap();
+        assert!((lufs + 18.0).abs() < 0.1, "{}", lufs);
+
+        // Asking for too much is limited by the peak.
+        normalize(&mut samples, 22050, 0.0);
+        let peak = samples.iter().fold(0.0_f32, |m, s| m.max(s.abs()));
+        assert!((peak - 1.0).abs() < 1e-4, "{}", peak);
+    }
+
+    #[test]
+    fn test_highpass() {
+        let mut low = sine(30.0, 0.5, 1.0, 22050);
+        let mut high = sine(1000.0, 0.5, 1.0, 22050);
+        highpass(&mut low, 22050, 120.0);
+        highpass(&mut high, 22050, 120.0);
+        assert!(rms(&low[11025..]) < 0.05, "{}", rms(&low[11025..]));
+        assert!(rms(&high[11025..]) > 0.34, "{}", rms(&high[11025..]));
+    }
+
+    #[test]
+    fn test_compressor_reduces_loud_parts() {
+        let spec = CompressSpec::default();
+        let mut loud = sine(440.0, 0.9, 1.0, 22050);
+        let mut quiet = sine(440.0, 0.01, 1.0, 22050);
+        compressor(&mut loud, 22050, &spec);
+        compressor(&mut quiet, 22050, &spec);
+        assert!(rms(&loud[11025..]) < 0.9 * 0.5, "{}", rms(&loud[11025..]));
+        assert!((rms(&quiet[11025..]) - 0.01 / 2f32.sqrt()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_reverb_tail() {
+        let dry = sine(440.0, 0.5, 0.5, 22050);
+        let wet = reverb(&dry, 22050, &ReverbSpec::default());
+        assert!(wet.len() > dry.len());
+        assert!(rms(&wet[dry.len()..]) > 0.0);
+        assert!(wet.iter().all(|s| s.is_finite() && s.abs() < 1.0));
+    }
+}
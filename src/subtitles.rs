@@ -0,0 +1,320 @@
+/// Subtitles and transcripts of the TTS in a session, timed from the generated clips.
+use crate::config::{Chunk, Overflow};
+use crate::markup;
+use log::{debug, warn};
+use std::error::Error;
+use std::fmt::Write as _;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubtitleFormat {
+    Srt,
+    Vtt,
+    Lrc,
+}
+
+impl SubtitleFormat {
+    /// Pick the format from the file extension.
+    pub fn from_path(path: &str) -> Result<Self, Box<dyn Error>> {
+        let ext = Path::new(path)
+            .extension()
+            .and_then(|s| s.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        match ext.as_str() {
+            "srt" => Ok(SubtitleFormat::Srt),
+            "vtt" => Ok(SubtitleFormat::Vtt),
+            "lrc" => Ok(SubtitleFormat::Lrc),
+            other => Err(format!(
+                "unknown subtitle extension {:?} for {:?}, expected srt, vtt or lrc",
+                other, path
+            )
+            .into()),
+        }
+    }
+}
+
+/// One line of speech, in seconds from the start of the session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cue {
+    pub start: f32,
+    pub end: f32,
+    pub text: String,
+}
+
+/// Find when each TTS mixin plays, from where its segment starts, its offset and the length of its
+/// clip. Mixins that can't be opened are left out, since the render reports or skips them itself.
+pub fn cues(chunks: &[Chunk], sample_rate: u32) -> Vec<Cue> {
+    let session_end = chunks.iter().map(|c| c.samples()).sum::<usize>() as f32 / sample_rate as f32;
+    let mut cues: Vec<Cue> = Vec::new();
+    let mut segment_start = 0.0_f32;
+    for chunk in chunks.iter() {
+        let segment_end = segment_start + chunk.samples() as f32 / sample_rate as f32;
+        for mixin in chunk.mixins() {
+            let Some(text) = &mixin.text else {
+                continue;
+            };
+            let secs = match mixin.duration() {
+                Ok(secs) => secs,
+                Err(err) => {
+                    debug!("no subtitle for {:?}: {}", mixin.path, err);
+                    continue;
+                }
+            };
+            let start = segment_start + mixin.offset;
+            let mut end = start + secs;
+            if mixin.overflow == Overflow::Truncate {
+                end = end.min(segment_end);
+            }
+            end = end.min(session_end);
+            if end <= start {
+                continue;
+            }
+            let text = markup::plain_text(text).unwrap_or_else(|_| text.trim().to_string());
+            cues.push(Cue { start, end, text });
+        }
+        segment_start = segment_end;
+    }
+    cues.sort_by(|a, b| a.start.total_cmp(&b.start));
+    for pair in cues.windows(2) {
+        if pair[1].start < pair[0].end {
+            warn!(
+                "subtitles overlap at {}: {:?} and {:?}",
+                srt_time(pair[1].start),
+                pair[0].text,
+                pair[1].text
+            );
+        }
+    }
+    cues
+}
+
+/// Write the cues to `path` in the format given by its extension.
+pub fn write(path: &str, cues: &[Cue]) -> Result<(), Box<dyn Error>> {
+    let text = format(SubtitleFormat::from_path(path)?, cues);
+    std::fs::write(path, text)?;
+    Ok(())
+}
+
+pub fn format(format: SubtitleFormat, cues: &[Cue]) -> String {
+    let mut out = String::new();
+    match format {
+        SubtitleFormat::Srt => {
+            for (i, cue) in cues.iter().enumerate() {
+                let _ = write!(
+                    out,
+                    "{}\n{} --> {}\n{}\n\n",
+                    i + 1,
+                    srt_time(cue.start),
+                    srt_time(cue.end),
+                    cue.text
+                );
+            }
+        }
+        SubtitleFormat::Vtt => {
+            out.push_str("WEBVTT\n\n");
+            for cue in cues.iter() {
+                let _ = write!(
+                    out,
+                    "{} --> {}\n{}\n\n",
+                    vtt_time(cue.start),
+                    vtt_time(cue.end),
+                    cue.text
+                );
+            }
+        }
+        SubtitleFormat::Lrc => {
+            for (i, cue) in cues.iter().enumerate() {
+                let _ = writeln!(out, "{}{}", lrc_time(cue.start), cue.text);
+                // Clear the line once it's been said, unless the next one starts right away.
+                let next_start = cues.get(i + 1).map(|c| c.start);
+                if next_start.is_none_or(|start| start > cue.end) {
+                    let _ = writeln!(out, "{}", lrc_time(cue.end));
+                }
+            }
+        }
+    }
+    out
+}
+
+fn millis(secs: f32) -> u64 {
+    (secs.max(0.0) as f64 * 1000.0).round() as u64
+}
+
+/// `HH:MM:SS,mmm`
+fn srt_time(secs: f32) -> String {
+    let ms = millis(secs);
+    format!(
+        "{:02}:{:02}:{:02},{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+/// `HH:MM:SS.mmm`
+fn vtt_time(secs: f32) -> String {
+    srt_time(secs).replace(',', ".")
+}
+
+/// `[MM:SS.cc]`, where minutes can go past 59.
+fn lrc_time(secs: f32) -> String {
+    let cs = (millis(secs) + 5) / 10;
+    format!("[{:02}:{:02}.{:02}]", cs / 6000, cs / 100 % 60, cs % 100)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ToneSpec;
+    use crate::mixin::Mixin;
+    use std::path::PathBuf;
+
+    fn write_clip(path: &Path, samples: usize) {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for _ in 0..samples {
+            writer.write_sample(0_i16).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    fn mixin(path: PathBuf, offset: f32, overflow: Overflow, text: Option<&str>) -> Mixin {
+        Mixin {
+            gain: 1.0,
+            path,
+            offset,
+            overflow,
+            segment: 0,
+            index: 0,
+            cache_dir: None,
+            decoded: None,
+            text: text.map(|t| t.to_string()),
+        }
+    }
+
+    fn tone(samples: usize, mixins: Vec<Mixin>) -> Chunk {
+        Chunk::Tone {
+            samples,
+            spec: ToneSpec {
+                gain: 1.0,
+                carrier: 100.0,
+                hz: 4.0,
+                noise: None,
+            },
+            mixins,
+            duck: None,
+            label: None,
+        }
+    }
+
+    #[test]
+    fn test_cues_from_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let clip = dir.path().join("clip.wav");
+        // 2 seconds at 100Hz
+        write_clip(&clip, 200);
+        let chunks = vec![
+            tone(
+                300,
+                vec![
+                    mixin(
+                        clip.clone(),
+                        0.5,
+                        Overflow::Continue,
+                        Some("One [pause 1s] two"),
+                    ),
+                    mixin(clip.clone(), 0.0, Overflow::Continue, None),
+                ],
+            ),
+            tone(
+                300,
+                vec![
+                    mixin(clip.clone(), 2.0, Overflow::Truncate, Some("Three")),
+                    mixin(
+                        dir.path().join("missing"),
+                        0.0,
+                        Overflow::Continue,
+                        Some("x"),
+                    ),
+                ],
+            ),
+        ];
+        let cues = cues(&chunks, 100);
+        assert_eq!(
+            cues,
+            vec![
+                Cue {
+                    start: 0.5,
+                    end: 2.5,
+                    text: "One two".to_string()
+                },
+                Cue {
+                    start: 5.0,
+                    end: 6.0,
+                    text: "Three".to_string()
+                },
+            ]
+        );
+    }
+
+    fn sample_cues() -> Vec<Cue> {
+        vec![
+            Cue {
+                start: 0.5,
+                end: 2.25,
+                text: "Welcome.".to_string(),
+            },
+            Cue {
+                start: 3725.0,
+                end: 3727.5,
+                text: "Breathe out.".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(
+            SubtitleFormat::from_path("a/out.SRT").unwrap(),
+            SubtitleFormat::Srt
+        );
+        assert_eq!(
+            SubtitleFormat::from_path("out.vtt").unwrap(),
+            SubtitleFormat::Vtt
+        );
+        assert!(SubtitleFormat::from_path("out.txt").is_err());
+    }
+
+    #[test]
+    fn test_srt() {
+        assert_eq!(
+            format(SubtitleFormat::Srt, &sample_cues()),
+            "1\n00:00:00,500 --> 00:00:02,250\nWelcome.\n\n\
+             2\n01:02:05,000 --> 01:02:07,500\nBreathe out.\n\n"
+        );
+    }
+
+    #[test]
+    fn test_vtt() {
+        assert_eq!(
+            format(SubtitleFormat::Vtt, &sample_cues()),
+            "WEBVTT\n\n00:00:00.500 --> 00:00:02.250\nWelcome.\n\n\
+             01:02:05.000 --> 01:02:07.500\nBreathe out.\n\n"
+        );
+    }
+
+    #[test]
+    fn test_lrc() {
+        assert_eq!(
+            format(SubtitleFormat::Lrc, &sample_cues()),
+            "[00:00.50]Welcome.\n[00:02.25]\n[62:05.00]Breathe out.\n[62:07.50]\n"
+        );
+    }
+}
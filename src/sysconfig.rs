@@ -24,9 +24,13 @@ pub fn get_models_dir() -> std::io::Result<PathBuf> {
     Ok(result)
 }
 
+/// Where every config's audio dir goes, unless it has an absolute `audio_dir`.
+pub fn get_audio_root() -> std::io::Result<PathBuf> {
+    Ok(get_sysconfig_dir()?.join("audio"))
+}
+
 pub fn get_audio_dir(audio_key: String) -> std::io::Result<PathBuf> {
-    let sysconfig_dir = get_sysconfig_dir()?;
-    let result = sysconfig_dir.join("audio").join(audio_key);
+    let result = get_audio_root()?.join(audio_key);
     if !result.exists() {
         info!("creating: {}", result.display());
     }
@@ -0,0 +1,184 @@
+/// Any other engine, run from a command template like "mytts --out {out} --voice {voice}".
+/// The template is split into arguments like a shell would, with quotes, but isn't run by a shell,
+/// so the text can't inject anything. The text is also written to the command's stdin.
+use super::{EngineError, EngineErrorKind, TtsBackend, TtsRequest};
+use regex::Regex;
+use std::process::Command;
+use std::sync::LazyLock;
+
+/// A placeholder in the template, eg: {out}
+static PLACEHOLDER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{([a-z_]+)\}").unwrap());
+
+pub struct CommandTemplate {
+    template: String,
+    /// The program, ie: the first word of the template
+    name: String,
+}
+
+impl CommandTemplate {
+    pub fn new(template: &str) -> Self {
+        let name = split(template)
+            .ok()
+            .and_then(|args| args.into_iter().next())
+            .unwrap_or_else(|| "command".to_string());
+        Self {
+            template: template.to_string(),
+            name,
+        }
+    }
+
+    fn error(&self, msg: String) -> EngineError {
+        EngineError::new(&self.name, EngineErrorKind::Config(msg))
+    }
+
+    /// Fill in a placeholder, eg: "{out}" is where to write the WAV file.
+    fn value(&self, placeholder: &str, req: &TtsRequest) -> Result<String, EngineError> {
+        let path = |p: Option<&std::path::Path>| p.map(|p| p.to_string_lossy().into_owned());
+        let value = match placeholder {
+            "out" => Some(req.out.to_string_lossy().into_owned()),
+            "text" => Some(req.text.to_string()),
+            "voice" => req.voice_name.map(|v| v.to_string()),
+            "model" => path(req.model),
+            "config" => path(req.config),
+            "length_scale" => req.voice.length_scale.map(|v| v.to_string()),
+            "speaker" => req.voice.speaker.map(|v| v.to_string()),
+            other => {
+                return Err(self.error(format!(
+                    "unknown placeholder {{{}}}, expected out, text, voice, model, config, length_scale or speaker",
+                    other
+                )));
+            }
+        };
+        value.ok_or_else(|| {
+            self.error(format!(
+                "cmd uses {{{}}}, but it isn't set for this line",
+                placeholder
+            ))
+        })
+    }
+}
+
+impl TtsBackend for CommandTemplate {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command(&self, req: &TtsRequest) -> Result<Command, EngineError> {
+        let mut args: Vec<String> = Vec::new();
+        for word in split(&self.template).map_err(|msg| self.error(msg))? {
+            let mut arg = String::new();
+            let mut last = 0;
+            for caps in PLACEHOLDER.captures_iter(&word) {
+                let whole = caps.get(0).unwrap();
+                arg.push_str(&word[last..whole.start()]);
+                arg.push_str(&self.value(&caps[1], req)?);
+                last = whole.end();
+            }
+            arg.push_str(&word[last..]);
+            args.push(arg);
+        }
+        if args.is_empty() {
+            return Err(self.error("the command engine needs a `cmd` template".to_string()));
+        }
+        let mut cmd = Command::new(&args[0]);
+        cmd.args(&args[1..]);
+        Ok(cmd)
+    }
+}
+
+/// Split on whitespace, keeping anything in single or double quotes together.
+fn split(template: &str) -> Result<Vec<String>, String> {
+    let mut words: Vec<String> = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote: Option<char> = None;
+    for c in template.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(format!("unclosed quote in cmd {:?}", template));
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::VoiceSpec;
+    use crate::tts::synthesize;
+    use std::path::Path;
+
+    fn request<'a>(voice: &'a VoiceSpec, out: &'a Path) -> TtsRequest<'a> {
+        TtsRequest {
+            text: "Breathe in; rm -rf /",
+            model: None,
+            config: None,
+            voice_name: Some("calm voice"),
+            voice,
+            out,
+        }
+    }
+
+    #[test]
+    fn test_split() {
+        assert_eq!(
+            split(r#"mytts --out {out}  --title "a b" 'c "d"' """#).unwrap(),
+            vec!["mytts", "--out", "{out}", "--title", "a b", "c \"d\"", ""]
+        );
+        assert!(split("mytts 'oops").is_err());
+    }
+
+    #[test]
+    fn test_command_args() {
+        let voice = VoiceSpec::default();
+        let req = request(&voice, Path::new("/tmp/out"));
+        let backend = CommandTemplate::new("mytts --out={out} --voice {voice} -t {text}");
+        assert_eq!(backend.name(), "mytts");
+        let cmd = backend.command(&req).unwrap();
+        let args: Vec<_> = cmd.get_args().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(
+            args,
+            vec![
+                "--out=/tmp/out",
+                "--voice",
+                "calm voice",
+                "-t",
+                "Breathe in; rm -rf /"
+            ]
+        );
+
+        let err = CommandTemplate::new("mytts {model}")
+            .command(&req)
+            .unwrap_err();
+        assert!(err.to_string().contains("{model}"), "{}", err);
+        let err = CommandTemplate::new("mytts {nope}")
+            .command(&req)
+            .unwrap_err();
+        assert!(err.to_string().contains("unknown placeholder"), "{}", err);
+        assert!(CommandTemplate::new("").command(&req).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_stub() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("_tts_test");
+        let voice = VoiceSpec::default();
+        let req = request(&voice, &out);
+        synthesize(&CommandTemplate::new("tee {out}"), &req).unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), req.text);
+
+        let err = synthesize(&CommandTemplate::new("sh -c 'exit 3'"), &req).unwrap_err();
+        assert!(matches!(err.kind, EngineErrorKind::Exit { .. }), "{}", err);
+        assert!(!out.exists());
+    }
+}
@@ -0,0 +1,71 @@
+/// espeak-ng, for a small robotic voice that's installed almost everywhere.
+use super::{EngineError, TtsBackend, TtsRequest};
+use log::debug;
+use std::process::Command;
+
+/// espeak-ng's own default speed, in words per minute.
+const DEFAULT_WPM: f32 = 175.0;
+
+pub struct EspeakNg {
+    bin: String,
+}
+
+impl Default for EspeakNg {
+    fn default() -> Self {
+        Self {
+            bin: "espeak-ng".to_string(),
+        }
+    }
+}
+
+impl TtsBackend for EspeakNg {
+    fn name(&self) -> &str {
+        &self.bin
+    }
+
+    fn command(&self, req: &TtsRequest) -> Result<Command, EngineError> {
+        let mut cmd = Command::new(&self.bin);
+        cmd.arg("--stdin").arg("-w").arg(req.out);
+        if let Some(voice) = req.voice_name {
+            cmd.arg("-v").arg(voice);
+        }
+        // A longer length scale is slower speech, like with piper.
+        if let Some(scale) = req.voice.length_scale.filter(|s| *s > 0.0) {
+            let wpm = (DEFAULT_WPM / scale).round() as u32;
+            cmd.arg("-s").arg(wpm.to_string());
+        }
+        if req.voice.speaker.is_some() || req.voice.noise_scale.is_some() {
+            debug!("espeak-ng ignores speaker and noise settings");
+        }
+        Ok(cmd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::VoiceSpec;
+    use std::path::Path;
+
+    #[test]
+    fn test_espeak_args() {
+        let voice = VoiceSpec {
+            length_scale: Some(2.0),
+            ..Default::default()
+        };
+        let req = TtsRequest {
+            text: "hello",
+            model: None,
+            config: None,
+            voice_name: Some("en-us"),
+            voice: &voice,
+            out: Path::new("/tmp/out"),
+        };
+        let cmd = EspeakNg::default().command(&req).unwrap();
+        let args: Vec<_> = cmd.get_args().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(
+            args,
+            vec!["--stdin", "-w", "/tmp/out", "-v", "en-us", "-s", "88"]
+        );
+    }
+}
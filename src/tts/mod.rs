@@ -0,0 +1,297 @@
+/// Text to speech, by running a local engine for each line.
+/// The default engine is piper, see its models in ./text_to_speech/models directory, eg:
+/// en_US-amy-medium.onnx
+/// I tried to integrate piper, but the ort dependency was killing me. It's much easier to just run
+/// their binary - and that source and linux x86/64 binary is included in the repo in
+/// ./text_to_speech
+/// espeak-ng, or any other engine through a command template, can be picked per line instead.
+use crate::config::{Engine, EngineSpec, VoiceSpec};
+use log::{debug, info};
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::thread;
+
+/// How much of the text to show when naming a TTS line in errors.
+const SNIPPET_CHARS: usize = 40;
+
+pub use command::CommandTemplate;
+pub use espeak::EspeakNg;
+pub use piper::{BatchLine, Piper, run_piper, run_piper_batch};
+mod command;
+mod espeak;
+mod piper;
+
+#[derive(Debug)]
+pub enum EngineErrorKind {
+    /// The engine couldn't be started, or we couldn't talk to it.
+    Io(std::io::Error),
+    /// The engine exited with an error status.
+    Exit { status: ExitStatus, stderr: String },
+    /// The engine exited successfully but the output file is missing or empty.
+    NoOutput { path: PathBuf, stderr: String },
+    /// The line's settings don't work with the engine, eg: a command template without `cmd`.
+    Config(String),
+}
+
+/// A TTS engine that failed to generate a clip.
+#[derive(Debug)]
+pub struct EngineError {
+    /// The program that was run, eg: "piper"
+    pub engine: String,
+    pub kind: EngineErrorKind,
+}
+
+impl EngineError {
+    pub fn new(engine: &str, kind: EngineErrorKind) -> Self {
+        Self {
+            engine: engine.to_string(),
+            kind,
+        }
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            EngineErrorKind::Io(err) => write!(f, "failed to run {}: {}", self.engine, err),
+            EngineErrorKind::Exit { status, stderr } => {
+                write!(f, "{} exited with {}", self.engine, status)?;
+                write_stderr(f, stderr)
+            }
+            EngineErrorKind::NoOutput { path, stderr } => {
+                write!(f, "{} wrote nothing to {}", self.engine, path.display())?;
+                write_stderr(f, stderr)
+            }
+            EngineErrorKind::Config(msg) => write!(f, "{}: {}", self.engine, msg),
+        }
+    }
+}
+
+fn write_stderr(f: &mut fmt::Formatter<'_>, stderr: &str) -> fmt::Result {
+    let stderr = stderr.trim();
+    if stderr.is_empty() {
+        Ok(())
+    } else {
+        write!(f, ", stderr:\n{}", stderr)
+    }
+}
+
+impl std::error::Error for EngineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            EngineErrorKind::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// A TTS line in the config that failed to generate.
+#[derive(Debug)]
+pub struct TtsError {
+    pub segment: usize,
+    pub index: usize,
+    /// The start of the text, enough to find it in the config.
+    pub snippet: String,
+    pub source: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl TtsError {
+    pub fn new(
+        segment: usize,
+        index: usize,
+        text: &str,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        Self {
+            segment,
+            index,
+            snippet: snippet(text),
+            source: source.into(),
+        }
+    }
+}
+
+impl fmt::Display for TtsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "segment {} mixin {} ({:?}): {}",
+            self.segment, self.index, self.snippet, self.source
+        )
+    }
+}
+
+impl std::error::Error for TtsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+/// The first few words of the text on one line, eg: "Bring awareness to your feet. Allow..."
+pub fn snippet(text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() <= SNIPPET_CHARS {
+        text
+    } else {
+        let cut: String = text.chars().take(SNIPPET_CHARS).collect();
+        format!("{}...", cut.trim_end())
+    }
+}
+
+/// Everything about one line an engine might need to speak it.
+#[derive(Debug, Clone, Copy)]
+pub struct TtsRequest<'a> {
+    pub text: &'a str,
+    pub model: Option<&'a Path>,
+    pub config: Option<&'a Path>,
+    /// The engine's own name for the voice, eg: "en-us" for espeak-ng
+    pub voice_name: Option<&'a str>,
+    pub voice: &'a VoiceSpec,
+    /// Where to write the WAV file
+    pub out: &'a Path,
+}
+
+/// A local TTS engine that writes a WAV file for a line of text.
+pub trait TtsBackend: Send + Sync {
+    /// The program that's run, for logs and errors.
+    fn name(&self) -> &str;
+    /// Set up the command that speaks the request. Its stdin gets the text.
+    fn command(&self, req: &TtsRequest) -> Result<Command, EngineError>;
+}
+
+/// The backend for a line's engine settings. `piper_bin` is the piper binary from the command
+/// line, if not just `piper` in the $PATH.
+pub fn backend(spec: &EngineSpec, piper_bin: Option<&str>) -> Box<dyn TtsBackend> {
+    match spec.engine.unwrap_or_default() {
+        Engine::Piper => Box::new(Piper::new(piper_bin)),
+        Engine::EspeakNg => Box::new(EspeakNg::default()),
+        Engine::Command => Box::new(CommandTemplate::new(
+            spec.cmd.as_deref().unwrap_or_default(),
+        )),
+    }
+}
+
+/// Speak the request with the backend. On failure the output file is removed, so the next run
+/// doesn't use what's left of it as a cached clip.
+pub fn synthesize(backend: &dyn TtsBackend, req: &TtsRequest) -> Result<(), EngineError> {
+    let res = backend
+        .command(req)
+        .and_then(|cmd| run_command(backend.name(), cmd, req.text, req.out));
+    if res.is_err() {
+        let _ = std::fs::remove_file(req.out);
+    }
+    res
+}
+
+/// Run an engine with the text on its stdin, and check it wrote something to `out`.
+fn run_command(name: &str, cmd: Command, text: &str, out: &Path) -> Result<(), EngineError> {
+    let stderr = run_process(name, cmd, text)?;
+    check_output(name, out, &stderr)?;
+    if !stderr.trim().is_empty() {
+        debug!("{} stderr:\n{}", name, stderr.trim());
+    }
+    info!("{} exited successfully and wrote: {:?}", name, out);
+    Ok(())
+}
+
+/// Run an engine with `input` on its stdin, and return its stderr if it exits successfully.
+fn run_process(name: &str, cmd: Command, input: &str) -> Result<String, EngineError> {
+    let output = run_with_input(name, cmd, input)?;
+    check_status(name, &output)?;
+    Ok(String::from_utf8_lossy(&output.stderr).into_owned())
+}
+
+/// Run an engine with `input` on its stdin, and collect its stdout and stderr whatever its exit
+/// status. The input is written from another thread while this one drains the output, or a long
+/// input and a chatty engine can each fill a pipe the other is waiting on.
+fn run_with_input(name: &str, mut cmd: Command, input: &str) -> Result<Output, EngineError> {
+    let io_err = |err: std::io::Error| EngineError::new(name, EngineErrorKind::Io(err));
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(io_err)?;
+
+    debug!("Spawned {} child, writing text to stdin...", name);
+    let stdin = child.stdin.take();
+    let (written, output) = thread::scope(|scope| {
+        // Write the text to stdin, and close it so the engine knows that's everything.
+        let writer = scope.spawn(move || match stdin {
+            Some(mut stdin) => stdin.write_all(input.as_bytes()),
+            None => Ok(()),
+        });
+        debug!("Waiting for {}...", name);
+        let output = child.wait_with_output();
+        let written = writer
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        (written, output)
+    });
+    let output = output.map_err(io_err)?;
+    match written {
+        // It quit before reading its input, or doesn't read it at all, so its exit status and
+        // stderr say whether anything's wrong.
+        Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => {
+            debug!("{} closed stdin early: {}", name, err)
+        }
+        res => res.map_err(io_err)?,
+    }
+    Ok(output)
+}
+
+fn check_status(name: &str, output: &Output) -> Result<(), EngineError> {
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(EngineError::new(
+        name,
+        EngineErrorKind::Exit {
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        },
+    ))
+}
+
+/// An engine that exited successfully should still have written something.
+fn check_output(name: &str, out: &Path, stderr: &str) -> Result<(), EngineError> {
+    let written = std::fs::metadata(out).map(|m| m.len()).unwrap_or(0);
+    if written == 0 {
+        return Err(EngineError::new(
+            name,
+            EngineErrorKind::NoOutput {
+                path: out.to_path_buf(),
+                stderr: stderr.to_string(),
+            },
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snippet() {
+        assert_eq!(
+            snippet("  Breathe in.\n Hold it.  "),
+            "Breathe in. Hold it."
+        );
+        assert_eq!(
+            snippet("Bring awareness to your feet. Allow them to soften and release."),
+            "Bring awareness to your feet. Allow them..."
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_long_input_and_output_dont_block() {
+        // Far more than a pipe holds, echoed straight back while it's still being written.
+        let input = "Breathe in. ".repeat(100_000);
+        let output = run_with_input("cat", Command::new("cat"), &input).unwrap();
+        assert_eq!(output.stdout.len(), input.len());
+    }
+}
@@ -0,0 +1,315 @@
+/// Piper, run as a binary with the model and voice settings as arguments.
+use super::{
+    EngineError, TtsBackend, TtsRequest, check_output, check_status, run_with_input, synthesize,
+};
+use crate::config::VoiceSpec;
+use log::info;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub struct Piper {
+    bin: String,
+}
+
+impl Piper {
+    pub fn new(bin: Option<&str>) -> Self {
+        Self {
+            bin: bin.unwrap_or("piper").to_string(),
+        }
+    }
+}
+
+impl TtsBackend for Piper {
+    fn name(&self) -> &str {
+        &self.bin
+    }
+
+    fn command(&self, req: &TtsRequest) -> Result<Command, EngineError> {
+        let model = req.model.unwrap_or(Path::new(""));
+        let mut cmd = self.base_command(model, req.config, req.voice);
+        cmd.arg("-f").arg(req.out);
+        if let Some(v) = req.voice.speaker {
+            cmd.arg("--speaker").arg(v.to_string());
+        }
+        Ok(cmd)
+    }
+}
+
+impl Piper {
+    /// The model, config and the voice settings that apply to every line. The speaker can differ
+    /// per line in a batch, so it's left to the caller.
+    fn base_command(&self, model: &Path, config: Option<&Path>, voice: &VoiceSpec) -> Command {
+        let config = config
+            .map(|c| c.to_path_buf())
+            .unwrap_or_else(|| PathBuf::from(format!("{}.json", model.display())));
+        let mut cmd = Command::new(&self.bin);
+        cmd.arg("-m").arg(model).arg("-c").arg(config);
+        if let Some(v) = voice.length_scale {
+            cmd.arg("--length_scale").arg(v.to_string());
+        }
+        if let Some(v) = voice.noise_scale {
+            cmd.arg("--noise_scale").arg(v.to_string());
+        }
+        if let Some(v) = voice.noise_w {
+            cmd.arg("--noise_w").arg(v.to_string());
+        }
+        if let Some(v) = voice.sentence_silence {
+            cmd.arg("--sentence_silence").arg(v.to_string());
+        }
+        cmd
+    }
+}
+
+/// One line of a piper batch.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchLine<'a> {
+    pub text: &'a str,
+    pub speaker: Option<u32>,
+    pub out: &'a Path,
+}
+
+impl BatchLine<'_> {
+    /// A line of piper's `--json-input`, eg: {"text":"Hello.","output_file":"/a/_tts_1"}
+    fn to_json(self) -> String {
+        let mut line = serde_json::json!({
+            "text": self.text,
+            "output_file": self.out.to_string_lossy(),
+        });
+        if let Some(speaker) = self.speaker {
+            line["speaker_id"] = speaker.into();
+        }
+        line.to_string()
+    }
+}
+
+/// Speak many lines with one piper process, so the model is only loaded once. They all share the
+/// model, config and voice settings, except the speaker. On failure, the index of the line that
+/// failed is returned with the error. Piper prints each output path once it's written, so lines
+/// before that keep their output, and the outputs from there on, which might not be complete, are
+/// removed.
+pub fn run_piper_batch(
+    piper_bin: Option<&str>,
+    model: &Path,
+    config: Option<&Path>,
+    voice: &VoiceSpec,
+    lines: &[BatchLine],
+) -> Result<(), (usize, EngineError)> {
+    let piper = Piper::new(piper_bin);
+    let mut cmd = piper.base_command(model, config, voice);
+    cmd.arg("--json-input");
+    let input: Vec<String> = lines.iter().map(|line| line.to_json()).collect();
+    info!(
+        "generating {} TTS lines with one piper process for {}",
+        lines.len(),
+        model.display()
+    );
+
+    let output =
+        run_with_input(piper.name(), cmd, &(input.join("\n") + "\n")).map_err(|err| (0, err))?;
+    if let Err(err) = check_status(piper.name(), &output) {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let written: Vec<&Path> = stdout.lines().map(|line| Path::new(line.trim())).collect();
+        let failed = lines
+            .iter()
+            .position(|line| !written.contains(&line.out))
+            .unwrap_or(0);
+        for line in lines[failed..].iter() {
+            let _ = std::fs::remove_file(line.out);
+        }
+        return Err((failed, err));
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for (i, line) in lines.iter().enumerate() {
+        check_output(piper.name(), line.out, &stderr).map_err(|err| (i, err))?;
+    }
+    info!("piper exited successfully and wrote {} lines", lines.len());
+    Ok(())
+}
+
+pub fn run_piper(
+    piper_bin: Option<&str>,
+    text: &str,
+    model_path: &str,
+    config_path: Option<&str>,
+    output_path: &str,
+    voice: &VoiceSpec,
+) -> Result<(), EngineError> {
+    let req = TtsRequest {
+        text,
+        model: Some(Path::new(model_path)),
+        config: config_path.map(Path::new),
+        voice_name: None,
+        voice,
+        out: Path::new(output_path),
+    };
+    synthesize(&Piper::new(piper_bin), &req)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tts::EngineErrorKind;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_piper_failures_remove_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("_tts_test");
+        let out_str = out.to_str().unwrap();
+        let voice = VoiceSpec::default();
+
+        std::fs::write(&out, b"partial").unwrap();
+        let err =
+            run_piper(Some("false"), "hello", "model.onnx", None, out_str, &voice).unwrap_err();
+        assert!(matches!(err.kind, EngineErrorKind::Exit { .. }), "{}", err);
+        assert!(!out.exists());
+
+        let err =
+            run_piper(Some("true"), "hello", "model.onnx", None, out_str, &voice).unwrap_err();
+        assert!(
+            matches!(err.kind, EngineErrorKind::NoOutput { .. }),
+            "{}",
+            err
+        );
+
+        let err = run_piper(
+            Some("/nonexistent/piper"),
+            "hello",
+            "model.onnx",
+            None,
+            out_str,
+            &voice,
+        )
+        .unwrap_err();
+        assert!(matches!(err.kind, EngineErrorKind::Io(_)), "{}", err);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_batch_failure_names_the_line() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let outs: Vec<PathBuf> = (0..3)
+            .map(|i| dir.path().join(format!("_tts_{}", i)))
+            .collect();
+        // Writes the first line, then dies partway through the second.
+        let script = dir.path().join("piper");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\ncat > /dev/null\nprintf RIFF > {0}\necho {0}\nprintf RI > {1}\nexit 1\n",
+                outs[0].display(),
+                outs[1].display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let lines: Vec<BatchLine> = outs
+            .iter()
+            .map(|out| BatchLine {
+                text: "hello",
+                speaker: None,
+                out,
+            })
+            .collect();
+
+        let (failed, err) = run_piper_batch(
+            script.to_str(),
+            Path::new("m.onnx"),
+            None,
+            &VoiceSpec::default(),
+            &lines,
+        )
+        .unwrap_err();
+        assert_eq!(failed, 1);
+        assert!(matches!(err.kind, EngineErrorKind::Exit { .. }), "{}", err);
+        assert!(outs[0].exists());
+        assert!(!outs[1].exists());
+    }
+
+    #[test]
+    fn test_piper_args() {
+        let voice = VoiceSpec {
+            length_scale: Some(1.5),
+            speaker: Some(3),
+            ..Default::default()
+        };
+        let req = TtsRequest {
+            text: "hello",
+            model: Some(Path::new("/m/a.onnx")),
+            config: None,
+            voice_name: None,
+            voice: &voice,
+            out: Path::new("/tmp/out"),
+        };
+        let cmd = Piper::new(None).command(&req).unwrap();
+        assert_eq!(cmd.get_program(), "piper");
+        let args: Vec<_> = cmd.get_args().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(
+            args,
+            vec![
+                "-m",
+                "/m/a.onnx",
+                "-c",
+                "/m/a.onnx.json",
+                "--length_scale",
+                "1.5",
+                "-f",
+                "/tmp/out",
+                "--speaker",
+                "3"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_batch_json() {
+        let line = BatchLine {
+            text: "Say \"hi\"\nthen rest.",
+            speaker: None,
+            out: Path::new("/a/_tts_1"),
+        };
+        assert_eq!(
+            line.to_json(),
+            r#"{"output_file":"/a/_tts_1","text":"Say \"hi\"\nthen rest."}"#
+        );
+        let line = BatchLine {
+            speaker: Some(4),
+            ..line
+        };
+        assert!(line.to_json().contains(r#""speaker_id":4"#));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_piper_batch_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let outs = [dir.path().join("_tts_1"), dir.path().join("_tts_2")];
+        let lines: Vec<BatchLine> = outs
+            .iter()
+            .map(|out| BatchLine {
+                text: "hello",
+                speaker: None,
+                out,
+            })
+            .collect();
+        let voice = VoiceSpec::default();
+        let model = Path::new("model.onnx");
+
+        std::fs::write(&outs[1], b"partial").unwrap();
+        let (i, err) = run_piper_batch(Some("false"), model, None, &voice, &lines).unwrap_err();
+        assert_eq!(i, 0);
+        assert!(matches!(err.kind, EngineErrorKind::Exit { .. }), "{}", err);
+        assert!(!outs[1].exists());
+
+        // The first line was written but the second wasn't.
+        std::fs::write(&outs[0], b"RIFF").unwrap();
+        let (i, err) = run_piper_batch(Some("true"), model, None, &voice, &lines).unwrap_err();
+        assert_eq!(i, 1);
+        assert!(
+            matches!(err.kind, EngineErrorKind::NoOutput { .. }),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,229 @@
+/// Checks a render still has the binaural beats it was asked for, once it's decoded again. Lossy
+/// codecs save bits by sharing what the two channels have in common, eg: joint stereo, and at
+/// low bitrates that can smear the few Hz between them that make the beat.
+use crate::analysis::peak_freq;
+use crate::config::Chunk;
+use crate::sink::{self, AudioFormat};
+use log::info;
+use std::error::Error;
+
+/// How far the measured beat can be from the config's, in Hz
+const TOLERANCE_HZ: f32 = 0.2;
+/// The longest stretch of each segment that's measured, which gives 0.25 Hz FFT bins
+const WINDOW_SECS: usize = 4;
+/// How far either side of the two tones to look for them, in Hz
+const SEARCH_HZ: f32 = 10.0;
+
+/// A stretch from the middle of a steady segment, and the beat it should have.
+#[derive(Debug)]
+pub struct BeatWindow {
+    start: usize,
+    len: usize,
+    carrier: f32,
+    hz: f32,
+    left: Vec<f32>,
+    right: Vec<f32>,
+}
+
+/// A window whose beat was off.
+#[derive(Debug)]
+pub struct BeatMismatch {
+    pub secs: f32,
+    pub expected: f32,
+    pub measured: Option<f32>,
+}
+
+/// Where to measure the beat in a render of these chunks. Transitions are left out, since their
+/// beat is moving, and so are silent or very short segments.
+pub fn beat_windows(chunks: &[Chunk], sample_rate: u32) -> Vec<BeatWindow> {
+    let sr = sample_rate as usize;
+    let mut windows = Vec::new();
+    let mut start = 0;
+    for chunk in chunks.iter() {
+        if let Chunk::Tone { samples, spec, .. } = chunk {
+            let len = (samples / 2).min(WINDOW_SECS * sr);
+            if spec.gain > 0.0 && len >= sr {
+                windows.push(BeatWindow {
+                    start: start + (samples - len) / 2,
+                    len,
+                    carrier: spec.carrier,
+                    hz: spec.hz,
+                    left: Vec::with_capacity(len),
+                    right: Vec::with_capacity(len),
+                });
+            }
+        }
+        start += chunk.samples();
+    }
+    windows
+}
+
+impl BeatWindow {
+    /// The beat in Hz between what's in the left and right channels.
+    fn measure(&self, sample_rate: u32) -> Option<f32> {
+        let lo = self.carrier.min(self.carrier + self.hz) - SEARCH_HZ;
+        let hi = self.carrier.max(self.carrier + self.hz) + SEARCH_HZ;
+        let left = peak_freq(&self.left, sample_rate, lo, hi)?;
+        let right = peak_freq(&self.right, sample_rate, lo, hi)?;
+        Some(right - left)
+    }
+}
+
+/// Decode `out` and measure the beat in each window, returning the ones that are off.
+pub fn check_beats(
+    out: &str,
+    format: AudioFormat,
+    mut windows: Vec<BeatWindow>,
+    sample_rate: u32,
+) -> Result<Vec<BeatMismatch>, Box<dyn Error>> {
+    let mut n = 0;
+    let mut current = 0;
+    let decoded_rate = sink::decode(out, format, &mut |l, r| {
+        while current < windows.len() && n >= windows[current].start + windows[current].len {
+            current += 1;
+        }
+        if let Some(w) = windows.get_mut(current)
+            && n >= w.start
+        {
+            w.left.push(l);
+            w.right.push(r);
+        }
+        n += 1;
+    })?;
+    if decoded_rate != sample_rate {
+        return Err(format!(
+            "verify: {} decoded at {} Hz rather than {} Hz",
+            out, decoded_rate, sample_rate
+        )
+        .into());
+    }
+    let mut mismatches = Vec::new();
+    for w in windows.iter() {
+        let measured = w.measure(sample_rate);
+        let ok = measured.is_some_and(|m| (m - w.hz).abs() <= TOLERANCE_HZ);
+        if !ok {
+            mismatches.push(BeatMismatch {
+                secs: w.start as f32 / sample_rate as f32,
+                expected: w.hz,
+                measured,
+            });
+        }
+    }
+    info!(
+        "Verified {} of {} beats in {:?} are within {} Hz",
+        windows.len() - mismatches.len(),
+        windows.len(),
+        out,
+        TOLERANCE_HZ
+    );
+    Ok(mismatches)
+}
+
+/// Check the beats in `out`, with an error naming each one that's off.
+pub fn verify(
+    out: &str,
+    format: AudioFormat,
+    windows: Vec<BeatWindow>,
+    sample_rate: u32,
+) -> Result<(), Box<dyn Error>> {
+    let mismatches = check_beats(out, format, windows, sample_rate)?;
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+    let lines: Vec<String> = mismatches
+        .iter()
+        .map(|m| match m.measured {
+            Some(hz) => format!(
+                "  at {:.1}s: expected a {:.2} Hz beat, measured {:.2} Hz",
+                m.secs, m.expected, hz
+            ),
+            None => format!(
+                "  at {:.1}s: expected a {:.2} Hz beat, but couldn't find the tones",
+                m.secs, m.expected
+            ),
+        })
+        .collect();
+    Err(format!(
+        "verify: {} beats in {:?} didn't survive encoding, try a higher bitrate:\n{}",
+        mismatches.len(),
+        out,
+        lines.join("\n")
+    )
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ToneSpec;
+    use crate::sink::{SinkOptions, new_sink};
+    use std::f32::consts::TAU;
+    use tempfile::tempdir;
+
+    fn tone(samples: usize, hz: f32) -> Chunk {
+        Chunk::Tone {
+            samples,
+            spec: ToneSpec {
+                carrier: 200.0,
+                hz,
+                gain: 0.5,
+                noise: None,
+            },
+            mixins: vec![],
+            duck: None,
+            label: None,
+        }
+    }
+
+    /// Write a WAV with the given beat in each second of `beats`.
+    fn write_wav(path: &str, sr: u32, beats: &[f32]) {
+        let opts = SinkOptions {
+            sample_rate: sr,
+            ..Default::default()
+        };
+        let mut sink = new_sink(path, &opts).unwrap();
+        let (mut phase_l, mut phase_r) = (0.0_f32, 0.0_f32);
+        for hz in beats.iter() {
+            for _ in 0..sr {
+                phase_l = (phase_l + 200.0 / sr as f32) % 1.0;
+                phase_r = (phase_r + (200.0 + hz) / sr as f32) % 1.0;
+                let (l, r) = ((TAU * phase_l).sin(), (TAU * phase_r).sin());
+                sink.write_frame(l * 0.5, r * 0.5).unwrap();
+            }
+        }
+        sink.finalize().unwrap();
+    }
+
+    #[test]
+    fn test_beat_windows() {
+        let sr = 1000;
+        let chunks = vec![tone(10_000, 4.0), tone(1500, 6.0), tone(3000, 8.0)];
+        let windows = beat_windows(&chunks, sr);
+        // The 1.5s segment is too short to measure.
+        assert_eq!(windows.len(), 2);
+        assert_eq!((windows[0].start, windows[0].len), (3000, 4000));
+        assert_eq!((windows[1].start, windows[1].len), (11_500 + 750, 1500));
+        assert_eq!(windows[1].hz, 8.0);
+    }
+
+    #[test]
+    fn test_check_beats() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("out.wav");
+        let path = path.to_str().unwrap();
+        let sr = 8000;
+        write_wav(path, sr, &[4.0; 8]);
+        let chunks = vec![tone(sr as usize * 8, 4.0)];
+        assert!(verify(path, AudioFormat::Wav, beat_windows(&chunks, sr), sr).is_ok());
+
+        // Claim a different beat for the second half than was written.
+        let chunks = vec![tone(sr as usize * 4, 4.0), tone(sr as usize * 4, 10.0)];
+        let mismatches =
+            check_beats(path, AudioFormat::Wav, beat_windows(&chunks, sr), sr).unwrap();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].expected, 10.0);
+        assert!((mismatches[0].measured.unwrap() - 4.0).abs() < 0.1);
+        let err = verify(path, AudioFormat::Wav, beat_windows(&chunks, sr), sr).unwrap_err();
+        assert!(err.to_string().contains("at 5.0s"), "{}", err);
+    }
+}